@@ -1,29 +1,42 @@
 use anyhow::Result;
-use serde_json::json;
+use async_trait::async_trait;
 use serde::{Serialize, Deserialize};
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
 use uuid::Uuid;
 use std::io::{self, Write};
 use crate::telemetry::post_telemetry;
 use crate::utils::{img_shrink, rgb_image_to_base64_png};
 use crate::env::ComputerEnvironment;
+use crate::config::AgentConfig;
+use crate::session::SessionStore;
+use crate::trace::{TraceWriter, TracedAction};
+use crate::agent::context::prune_screenshots;
+use crate::agent::pricing::estimate_cost;
+use crate::agent::prompt::render_system_prompt;
+use crate::agent::output::{OutputSchema, MAX_RESULT_REMINDERS, RESULT_REMINDER, RESULT_TOOL_NAME};
 use std::time::Duration;
+use tokio::time::Instant;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct ApiResponse {
-    content: Vec<ContentBlock>,
-    stop_reason: String
-}
+pub mod anthropic;
+pub mod context;
+#[cfg(test)]
+pub mod mock;
+pub mod openai;
+pub mod output;
+pub mod pricing;
+pub mod prompt;
+pub mod retry;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Message {
-    role: String,
-    content: Vec<ContentBlock>
+    pub role: String,
+    pub content: Vec<ContentBlock>
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "type")]
-enum ContentBlock {
+pub enum ContentBlock {
     #[serde(rename = "text")]
     Text { text: String },
     #[serde(rename = "image")]
@@ -34,7 +47,8 @@ enum ContentBlock {
     ToolUse {
         id: String,
         name: String,
-        input: ToolInput,
+        /// Kept as is, since only the input of the computer tool is a `ToolInput`
+        input: serde_json::Value,
     },
     #[serde(rename = "tool_result")]
     ToolResult {
@@ -42,42 +56,95 @@ enum ContentBlock {
         tool_use_id: String,
         is_error: bool
     },
+    /// Extended thinking, which must be sent back unchanged with the following tool results
+    #[serde(rename = "thinking")]
+    Thinking { thinking: String, signature: String },
+    #[serde(rename = "redacted_thinking")]
+    RedactedThinking { data: String },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "action")]
 pub enum ToolInput {
     #[serde(rename = "screenshot")]
     Screenshot,
+    #[serde(rename = "mouse_move")]
+    MouseMove { coordinate: [u32; 2] },
+    #[serde(rename = "cursor_position")]
+    CursorPosition,
+    // Clicks happen at the current position if no coordinate is given, while holding the
+    // modifier keys in `text` (e.g. "shift" or "ctrl+alt") if given
     #[serde(rename = "left_click")]
-    LeftClick { coordinate: [u32; 2] },
+    LeftClick {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coordinate: Option<[u32; 2]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>
+    },
     #[serde(rename = "right_click")]
-    RightClick { coordinate: [u32; 2] },
+    RightClick {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coordinate: Option<[u32; 2]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>
+    },
+    #[serde(rename = "middle_click")]
+    MiddleClick {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coordinate: Option<[u32; 2]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>
+    },
     #[serde(rename = "double_click")]
-    DoubleClick { coordinate: [u32; 2] },
+    DoubleClick {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coordinate: Option<[u32; 2]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>
+    },
     #[serde(rename = "triple_click")]
-    TripleClick { coordinate: [u32; 2] },
+    TripleClick {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coordinate: Option<[u32; 2]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>
+    },
     #[serde(rename = "type")]
     Type { text: String },
     #[serde(rename = "key")]
     Key { text: String },
     #[serde(rename = "scroll")]
-    Scroll { coordinate: [u32; 2], scroll_direction: String, scroll_amount: u32 },
+    Scroll {
+        coordinate: [u32; 2],
+        scroll_direction: String,
+        scroll_amount: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>
+    },
+    // Durations are in seconds, and may be fractional
     #[serde(rename = "hold_key")]
-    HoldKey { text: String, duration: u32 },
+    HoldKey { text: String, duration: f64 },
     #[serde(rename = "left_mouse_down")]
     LeftMouseDown,
     #[serde(rename = "left_mouse_up")]
     LeftMouseUp,
     #[serde(rename = "left_click_drag")]
-    LeftClickDrag { coordinate: [u32; 2] },
+    LeftClickDrag {
+        /// Where the drag starts, the current position if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start_coordinate: Option<[u32; 2]>,
+        coordinate: [u32; 2]
+    },
     #[serde(rename = "wait")]
-    Wait { duration: u32 },
+    Wait { duration: f64 },
     #[serde(rename = "zoom")]
     Zoom { region: [u32; 4] },
+    /// An action we don't support, or with invalid parameters
+    #[serde(untagged)]
+    Other(serde_json::Value),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum ImageSource {
     #[serde(rename = "base64")]
@@ -87,179 +154,407 @@ pub enum ImageSource {
     },
 }
 
-const ANTHROPIC_MAX_WIDTH: u32 = 1024;
-const ANTHROPIC_MAX_HEIGHT: u32 = 768;
+impl ToolInput {
+    /// Parse the input of the computer tool
+    pub fn parse(input: serde_json::Value) -> Self {
+        serde_json::from_value(input.clone()).unwrap_or(ToolInput::Other(input))
+    }
+
+    /// The coordinate the action targets, in screenshot space
+    pub fn coordinate(&self) -> Option<[u32; 2]> {
+        match self {
+            ToolInput::MouseMove { coordinate }
+            | ToolInput::Scroll { coordinate, .. }
+            | ToolInput::LeftClickDrag { coordinate, .. } => Some(*coordinate),
+            ToolInput::LeftClick { coordinate, .. }
+            | ToolInput::RightClick { coordinate, .. }
+            | ToolInput::MiddleClick { coordinate, .. }
+            | ToolInput::DoubleClick { coordinate, .. }
+            | ToolInput::TripleClick { coordinate, .. } => *coordinate,
+            _ => None
+        }
+    }
+}
+
+impl ContentBlock {
+    pub fn png_image(img: &image::RgbImage) -> Result<Self> {
+        Ok(ContentBlock::Image { source: ImageSource::Base64 {
+            media_type: "image/png".to_string(),
+            data: rgb_image_to_base64_png(img)?
+        }})
+    }
+}
+
+/// Token counts reported by the model API for a single request
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+    }
+}
+
+/// A model response normalized to content blocks, where actions are `ToolUse` blocks
+/// named "computer" with a `ToolInput` in screenshot coordinates as input.
+#[derive(Debug)]
+pub struct ModelResponse {
+    pub content: Vec<ContentBlock>,
+    pub usage: Usage,
+}
+
+/// Emitted by providers while a response is generated, so that text can be shown and
+/// actions executed before the full response has arrived
+#[derive(Debug)]
+pub enum ModelEvent {
+    /// A piece of a text block that is still being streamed
+    TextDelta(String),
+    /// A piece of a thinking block that is still being streamed
+    ThinkingDelta(String),
+    /// A completed content block
+    Block(ContentBlock),
+}
+
+pub type ModelEvents = mpsc::UnboundedSender<ModelEvent>;
+
+/// A client side tool offered to the model in addition to the computer tool
+#[derive(Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub input_schema: serde_json::Value,
+}
+
+/// A computer use model backend. Providers translate the conversation into their own
+/// API format and normalize the reply, so the agent loop can stay backend agnostic.
+#[async_trait(?Send)]
+pub trait ModelProvider {
+    /// Model id, used to look up token prices
+    fn model(&self) -> &str;
+
+    /// Largest screenshot (width, height) to send, screenshots are shrunk to fit
+    fn max_screenshot_size(&self) -> (u32, u32);
+
+    /// Request the next response, with `system` as additional instructions and `tools` offered
+    /// next to the computer tool. Every content block of the response must also be sent to
+    /// `events` as soon as it is complete. Providers that don't stream send them all at the end.
+    async fn get_response(&self, system: Option<&str>, tools: &[ToolDefinition], display_width_px: u32, display_height_px: u32, messages: &[Message], events: ModelEvents) -> Result<ModelResponse>;
+}
+
+/// A budget that ends the session when exhausted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Actions(u32),
+    ApiCalls(u32),
+    Tokens(u64),
+    Cost(f64),
+    Time(Duration),
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Actions(n) => write!(f, "max actions ({})", n),
+            Limit::ApiCalls(n) => write!(f, "max API calls ({})", n),
+            Limit::Tokens(n) => write!(f, "max total tokens ({})", n),
+            Limit::Cost(usd) => write!(f, "max cost (${:.2})", usd),
+            Limit::Time(duration) => write!(f, "timeout ({}s)", duration.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    /// The model finished and the user ended the session, or it finished in non-interactive mode
+    Completed,
+    LimitReached(Limit),
+}
+
+/// How a session ended, along with the last message from the model and the last screenshot
+pub struct RunOutcome {
+    pub stop_reason: StopReason,
+    pub final_message: Option<String>,
+    /// The submitted result, if an output schema was given
+    pub output: Option<serde_json::Value>,
+    pub final_screenshot: image::RgbImage,
+}
+
+/// Tool results of a response, and whether it ends the session
+#[derive(Default)]
+struct Step {
+    /// The completed blocks of the response, kept in case the request fails after some were handled
+    content: Vec<ContentBlock>,
+    tool_results: Vec<ContentBlock>,
+    /// A limit was reached before all actions were executed
+    limit: Option<Limit>,
+    /// A result matching the output schema was submitted
+    output: Option<serde_json::Value>,
+}
+
+/// Errors that end a session, by what failed
+#[derive(Debug, thiserror::Error)]
+pub enum AgentError {
+    #[error("Model API request failed: {0:#}")]
+    Api(anyhow::Error),
+    #[error("{0:#}")]
+    Environment(anyhow::Error),
+}
+
+/// ANSI codes for dimmed terminal output, used for the model's thinking
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Screenshots are pruned in batches, so the cached conversation prefix only changes every few steps
+const PRUNE_BATCH_SIZE: usize = 5;
 
-pub struct AnthropicAgent {
-    client: reqwest::Client,
-    api_key: String,
-    model: String,
+pub struct Agent {
+    provider: Box<dyn ModelProvider>,
+    config: AgentConfig,
+    output_schema: Option<OutputSchema>,
+    trace: std::cell::RefCell<Option<TraceWriter>>,
     pub session_id: String,
+    /// Save the transcript after every step, so that the session can be resumed
+    pub save_session: bool,
     pub action_count: std::cell::Cell<u32>,
+    pub api_calls: std::cell::Cell<u32>,
+    pub usage: std::cell::Cell<Usage>,
 }
 
-impl AnthropicAgent {
-    pub async fn create(model: String) -> Result<Self> {
-        let client = reqwest::Client::new();
-        let api_key = std::env::var("ANTHROPIC_API_KEY")?;
-        let agent = AnthropicAgent {
-            client,
-            api_key,
-            model,
+impl Agent {
+    pub fn create(provider: Box<dyn ModelProvider>, config: AgentConfig, output_schema: Option<OutputSchema>) -> Self {
+        Agent {
+            provider,
+            config,
+            output_schema,
+            trace: std::cell::RefCell::new(None),
             session_id: Uuid::new_v4().to_string(),
+            save_session: true,
             action_count: std::cell::Cell::new(0),
-        };
+            api_calls: std::cell::Cell::new(0),
+            usage: std::cell::Cell::new(Usage::default()),
+        }
+    }
+
+    fn format_cost(&self, usage: &Usage) -> String {
+        match estimate_cost(self.provider.model(), usage) {
+            Some(cost) => format!("${:.4}", cost),
+            None => "unknown cost".to_string()
+        }
+    }
+
+    /// Add the usage of a response to the session total and print both
+    fn record_usage(&self, usage: Usage) {
+        let mut total = self.usage.get();
+        total += usage;
+        self.usage.set(total);
+        self.api_calls.set(self.api_calls.get() + 1);
+        println!(
+            "  [tokens: {} in, {} out, {} cache read, {} cache write | step {}, session {}]",
+            usage.input_tokens, usage.output_tokens, usage.cache_read_input_tokens, usage.cache_creation_input_tokens,
+            self.format_cost(&usage), self.format_cost(&total)
+        );
+    }
 
-        Ok(agent)
+    /// Record every executed action to `trace`
+    pub fn set_trace(&mut self, trace: TraceWriter) {
+        self.trace = std::cell::RefCell::new(Some(trace));
     }
 
-    pub async fn run(&self, env: &mut Box<dyn ComputerEnvironment>, prompt: &str, send_telemetry: bool) -> Result<()> {
-        let mut screenshot = img_shrink(env.screenshot()?, ANTHROPIC_MAX_WIDTH, ANTHROPIC_MAX_HEIGHT);
-        let mut scale: f32 = screenshot.width() as f32 / env.width()? as f32; // Scale relative environment
-        let mut messages: Vec<Message> = vec![
-            Message { role: "user".to_string(), content: vec![
+    fn record_trace(&self, action: TracedAction) {
+        let mut trace = self.trace.borrow_mut();
+        if let Some(writer) = trace.as_mut()
+            && let Err(e) = writer.record(action) {
+            eprintln!("Warning: Failed to write the trace, it will not be written again: {:#}", e);
+            *trace = None;
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        self.provider.model()
+    }
+
+    pub fn has_output_schema(&self) -> bool {
+        self.output_schema.is_some()
+    }
+
+    /// Estimated cost in USD of the session so far, if the model's price is known
+    pub fn estimated_cost(&self) -> Option<f64> {
+        estimate_cost(self.provider.model(), &self.usage.get())
+    }
+
+    /// Print the accumulated token usage and estimated cost of the session
+    pub fn print_summary(&self) {
+        let usage = self.usage.get();
+        println!("\nSession summary ({}):", self.provider.model());
+        println!("  Actions:            {}", self.action_count.get());
+        println!("  API calls:          {}", self.api_calls.get());
+        println!("  Input tokens:       {}", usage.input_tokens);
+        println!("  Output tokens:      {}", usage.output_tokens);
+        println!("  Cache read tokens:  {}", usage.cache_read_input_tokens);
+        println!("  Cache write tokens: {}", usage.cache_creation_input_tokens);
+        println!("  Estimated cost:     {}", self.format_cost(&usage));
+    }
+
+    /// The first configured budget that has been used up, if any
+    fn exceeded_limit(&self, started: Instant) -> Option<Limit> {
+        let config = &self.config;
+        let usage = self.usage.get();
+        if let Some(max) = config.max_actions.filter(|max| self.action_count.get() >= *max) {
+            return Some(Limit::Actions(max));
+        }
+        if let Some(max) = config.max_api_calls.filter(|max| self.api_calls.get() >= *max) {
+            return Some(Limit::ApiCalls(max));
+        }
+        let tokens = usage.input_tokens + usage.output_tokens + usage.cache_creation_input_tokens + usage.cache_read_input_tokens;
+        if let Some(max) = config.max_total_tokens.filter(|max| tokens >= *max) {
+            return Some(Limit::Tokens(max));
+        }
+        let cost = estimate_cost(self.provider.model(), &usage).unwrap_or(0.0);
+        if let Some(max) = config.max_cost.filter(|max| cost >= *max) {
+            return Some(Limit::Cost(max));
+        }
+        if let Some(timeout) = config.timeout.map(Duration::from_secs).filter(|t| started.elapsed() >= *t) {
+            return Some(Limit::Time(timeout));
+        }
+        None
+    }
+
+    fn take_screenshot(&self, env: &dyn ComputerEnvironment) -> Result<(image::RgbImage, f32)> {
+        let (max_width, max_height) = self.provider.max_screenshot_size();
+        let screenshot = img_shrink(env.screenshot()?, max_width, max_height);
+        let scale = screenshot.width() as f32 / env.width()? as f32; // Scale relative environment
+        Ok((screenshot, scale))
+    }
+
+    /// Run the task in `prompt`, or continue the conversation in `history` of a resumed session
+    pub async fn run(&self, env: &mut Box<dyn ComputerEnvironment>, prompt: &str, history: Vec<Message>, send_telemetry: bool) -> Result<RunOutcome> {
+        let started = Instant::now();
+        if self.config.max_cost.is_some() && estimate_cost(self.provider.model(), &Usage::default()).is_none() {
+            eprintln!("Warning: No known price for model {}, the cost limit will not apply", self.provider.model());
+        }
+        let system_prompt = self.config.system_prompt.as_deref()
+            .map(|template| render_system_prompt(template, env.as_ref()))
+            .transpose()
+            .map_err(AgentError::Environment)?;
+        let (mut screenshot, mut scale) = self.take_screenshot(env.as_ref()).map_err(AgentError::Environment)?;
+        let mut final_message = None;
+        let tools: Vec<ToolDefinition> = self.output_schema.iter().map(|schema| schema.tool()).collect();
+        let mut result_reminders = 0;
+        let mut messages = history;
+        if messages.is_empty() {
+            messages.push(Message { role: "user".to_string(), content: vec![
                 ContentBlock::Text { text: prompt.to_string() },
-                ContentBlock::Image { source: ImageSource::Base64 {
-                    media_type: "image/png".to_string(),
-                    data: rgb_image_to_base64_png(&screenshot)?
-                }}
-            ]}
-        ];
+                ContentBlock::png_image(&screenshot)?
+            ]});
+        } else {
+            resume_conversation(&mut messages, prompt, &screenshot)?;
+        }
+
+        let mut store = match SessionStore::open(&self.session_id) {
+            Ok(_) if !self.save_session => None,
+            Ok(store) => {
+                println!("Saving session to {}", store.dir().display());
+                Some(store)
+            }
+            Err(e) => {
+                eprintln!("Warning: The session will not be saved: {:#}", e);
+                None
+            }
+        };
 
         let stdin = BufReader::new(tokio::io::stdin());
         let mut lines = stdin.lines();
 
         loop {
-            let resp = self.get_response(
-                screenshot.width(),
-                screenshot.height(),
-                &messages
-            ).await?;
-            if !resp.status().is_success() {
-                return Err(anyhow::anyhow!("Anthropic API request failed with status: {}\n{}", resp.status(), resp.text().await?));
+            self.save_session(&mut store, &messages);
+            // Only the request is pruned, the saved transcript keeps every screenshot
+            let mut request = messages.clone();
+            if let Some(max_screenshots) = self.config.max_screenshots {
+                prune_screenshots(&mut request, max_screenshots, PRUNE_BATCH_SIZE);
             }
-            let text = resp.text().await?;
-            let res: ApiResponse = match serde_json::from_str(&text) {
-                Ok(val) => val,
-                Err(e) => {
-                    eprintln!("Failed to parse response: {e}\nResponse body:\n{text}");
-                    return Err(e.into());
+
+            if let Some(limit) = self.exceeded_limit(started) {
+                return self.stop_at_limit(limit, env.as_ref(), final_message, screenshot, send_telemetry).await;
+            }
+
+            // Handle the response as it is generated, executing actions as soon as they arrive
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let response = self.provider.get_response(system_prompt.as_deref(), &tools, screenshot.width(), screenshot.height(), &request, tx);
+            tokio::pin!(response);
+            // The handling of the events ends with the response, or before it once a limit is
+            // reached between actions. The rest of the response isn't needed then.
+            let mut res = None;
+            let step = {
+                let mut checkpoint = |step: &Step| self.save_step(&mut store, &messages, step);
+                let handling = self.handle_events(env.as_mut(), &mut rx, &mut screenshot, &mut scale, started, &mut checkpoint);
+                tokio::pin!(handling);
+                loop {
+                    tokio::select! {
+                        response_result = &mut response, if res.is_none() => res = Some(response_result),
+                        events_result = &mut handling => break events_result,
+                    }
                 }
             };
+            let step = step.map_err(AgentError::Environment)?;
             let mut next_message = Message {
                 role: "user".to_string(),
-                content: vec![]
-                };
-                for block in &res.content {
-                match block {
-                    ContentBlock::Text {text} => {
-                        println!("\nUI-Act:\n{}", text);
+                content: step.tool_results
+            };
+            if let Some(limit) = step.limit {
+                if let Some(Ok(res)) = res {
+                    self.record_usage(res.usage);
+                }
+                final_message = response_text(&step.content).or(final_message);
+                // Save the handled part of the response too, with the actions left out marked as such
+                if !step.content.is_empty() {
+                    let reason = format!("Not executed, {} was reached", limit);
+                    next_message.content.extend(skipped_results(&step.content, &next_message.content, &reason));
+                    messages.push(Message { role: "assistant".to_string(), content: step.content });
+                    if !next_message.content.is_empty() {
+                        messages.push(next_message);
                     }
-                    ContentBlock::ToolUse { name, input, id } => {
-                        println!("  {:?}", input);
-                        if name == "computer" {
-                            self.action_count.set(self.action_count.get() + 1);
-                            match input {
-                                ToolInput::LeftClick { coordinate } => {
-                                    let x = (coordinate[0] as f32 / scale).round() as u32;
-                                    let y = (coordinate[1] as f32 / scale).round() as u32;
-                                    env.mouse_move(x, y)?;
-                                    env.left_click()?;
-                                }
-                                ToolInput::RightClick { coordinate } => {
-                                    let x = (coordinate[0] as f32 / scale).round() as u32;
-                                    let y = (coordinate[1] as f32 / scale).round() as u32;
-                                    env.mouse_move(x, y)?;
-                                    env.right_click()?;
-                                }
-                                ToolInput::DoubleClick { coordinate } => {
-                                    let x = (coordinate[0] as f32 / scale).round() as u32;
-                                    let y = (coordinate[1] as f32 / scale).round() as u32;
-                                    env.mouse_move(x, y)?;
-                                    env.double_click()?;
-                                }
-                                ToolInput::TripleClick { coordinate } => {
-                                    let x = (coordinate[0] as f32 / scale).round() as u32;
-                                    let y = (coordinate[1] as f32 / scale).round() as u32;
-                                    env.mouse_move(x, y)?;
-                                    env.triple_click()?;
-                                }
-                                ToolInput::Type { text } => {
-                                    env.type_text(&text)?;
-                                }
-                                ToolInput::Key { text } => {
-                                    env.press_key(text)?;
-                                }
-                                ToolInput::Scroll { coordinate, scroll_direction, scroll_amount } => {
-                                    let x = (coordinate[0] as f32 / scale).round() as u32;
-                                    let y = (coordinate[1] as f32 / scale).round() as u32;
-                                    env.mouse_move(x, y)?;
-                                    env.scroll(scroll_direction, *scroll_amount)?;
-                                }
-                                ToolInput::HoldKey { text, duration } => {
-                                    env.hold_key(text, Duration::from_secs(*duration as u64))?;
-                                }
-                                ToolInput::LeftMouseDown => {
-                                    env.left_mouse_down()?;
-                                }
-                                ToolInput::LeftMouseUp => {
-                                    env.left_mouse_up()?;
-                                }
-                                ToolInput::LeftClickDrag { coordinate } => {
-                                    let x = (coordinate[0] as f32 / scale).round() as u32;
-                                    let y = (coordinate[1] as f32 / scale).round() as u32;
-                                    env.left_click_drag(x, y)?;
-                                }
-                                ToolInput::Wait { duration } => {
-                                    env.wait(Duration::from_secs(*duration as u64))?;
-                                }
-                                ToolInput::Screenshot => {
-                                    // Do nothing, screenshot will be provided below
-                                }
-                                ToolInput::Zoom { region } => {
-                                    let x1 = region[0].min(screenshot.width());
-                                    let y1 = region[1].min(screenshot.height());
-                                    let x2 = region[2].min(screenshot.width());
-                                    let y2 = region[3].min(screenshot.height());
-                                    let w = x2.saturating_sub(x1);
-                                    let h = y2.saturating_sub(y1);
-                                    let cropped = image::imageops::crop_imm(&screenshot, x1, y1, w, h).to_image();
-                                    next_message.content.push(ContentBlock::ToolResult {
-                                        content: vec![ContentBlock::Image { source: ImageSource::Base64 {
-                                            media_type: "image/png".to_string(),
-                                            data: rgb_image_to_base64_png(&cropped)?
-                                        }}],
-                                        tool_use_id: id.clone(),
-                                        is_error: false
-                                    });
-                                    continue;
-                                }
-                            }
-
-                            // Add a small delay after tool execution to allow UI to update
-                            tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-
-                            // Send new screenshot as tool result
-                            screenshot = img_shrink(env.screenshot()?, ANTHROPIC_MAX_WIDTH, ANTHROPIC_MAX_HEIGHT);
-                            scale = screenshot.width() as f32 / env.width()? as f32;
-                            next_message.content.push(ContentBlock::ToolResult {
-                                content: vec![ContentBlock::Image { source: ImageSource::Base64 {
-                                    media_type: "image/png".to_string(),
-                                    data: rgb_image_to_base64_png(&screenshot)?
-                                }}],
-                                tool_use_id: id.clone(),
-                                is_error: false
-                            })
-                        }
+                    self.save_session(&mut store, &messages);
+                }
+                return self.stop_at_limit(limit, env.as_ref(), final_message, screenshot, send_telemetry).await;
+            }
+            let res = match res {
+                Some(res) => res,
+                None => response.await
+            };
+            let res = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    if !next_message.content.is_empty() {
+                        // Actions were executed before the response failed, keep them so they aren't repeated
+                        messages.push(Message { role: "assistant".to_string(), content: step.content });
+                        messages.push(next_message);
                     }
-                    ContentBlock::Image { .. } => {
-                        println!("Image block in response, ignored");
+                    self.save_session(&mut store, &messages);
+                    if self.config.non_interactive {
+                        return Err(AgentError::Api(e).into());
                     }
-                    _ => {
-                        return Err(anyhow::anyhow!("Unknown content block variant encountered"));
+                    // The conversation is intact, so the user may resume once the API is back
+                    eprintln!("\nModel request failed: {:#}", e);
+                    println!("Press enter to retry or type exit to quit...");
+                    print!("\n> ");
+                    io::stdout().flush()?;
+                    match lines.next_line().await? {
+                        Some(line) if !line.trim().eq_ignore_ascii_case("exit") => continue,
+                        _ => return Err(AgentError::Api(e).into())
                     }
                 }
-            }
+            };
+            self.record_usage(res.usage);
+            final_message = response_text(&res.content).or(final_message);
 
             // Add response to messages
             messages.push(Message {
@@ -267,10 +562,28 @@ impl AnthropicAgent {
                 content: res.content
             });
 
+            if step.output.is_some() {
+                messages.push(next_message);
+                self.save_session(&mut store, &messages);
+                self.post_session_end(env.as_ref(), "success", send_telemetry).await;
+                return Ok(RunOutcome { stop_reason: StopReason::Completed, final_message, output: step.output, final_screenshot: screenshot });
+            }
+            if next_message.content.is_empty() && self.output_schema.is_some() && result_reminders < MAX_RESULT_REMINDERS {
+                // The model must finish by submitting a result
+                println!("  Reminding the model to submit a result");
+                result_reminders += 1;
+                next_message.content.push(ContentBlock::Text { text: RESULT_REMINDER.to_string() });
+            }
+
             // Maybe prompt user
-            if next_message.content.len() == 0 {
+            if next_message.content.is_empty() && self.config.non_interactive {
+                // No tool result, the model is done
+                self.save_session(&mut store, &messages);
+                self.post_session_end(env.as_ref(), "success", send_telemetry).await;
+                break;
+            }
+            if next_message.content.is_empty() {
                 // No tool result, ask for user input
-                use std::io::{self, Write};
                 println!("Type a message or press enter to exit...");
                 print!("\n> ");
                 io::stdout().flush()?;
@@ -278,30 +591,15 @@ impl AnthropicAgent {
                 if let Some(line) = lines.next_line().await? {
                     let input = line.trim();
                     if input.is_empty() || input.eq_ignore_ascii_case("exit") {
-                        // Send session end telemetry
-                        if send_telemetry {
-                            post_telemetry(
-                                &self.session_id, 
-                                &env.name(), 
-                                "session_end", 
-                                Some("success"), 
-                                Some(self.action_count.get())
-                            ).await;
-                        }
+                        self.save_session(&mut store, &messages);
+                        self.post_session_end(env.as_ref(), "success", send_telemetry).await;
                         break;
                     }
                     next_message.content.push(ContentBlock::Text { text: input.to_string() })
                 } else {
                     // EOF (Ctrl-D or terminal closed)
-                    if send_telemetry {
-                        post_telemetry(
-                            &self.session_id, 
-                            &env.name(), 
-                            "session_end", 
-                            Some("success"), 
-                            Some(self.action_count.get())
-                        ).await;
-                    }
+                    self.save_session(&mut store, &messages);
+                    self.post_session_end(env.as_ref(), "success", send_telemetry).await;
                     break;
                 }
             }
@@ -310,31 +608,749 @@ impl AnthropicAgent {
             messages.push(next_message)
         }
 
-        Ok(())
+        Ok(RunOutcome { stop_reason: StopReason::Completed, final_message, output: None, final_screenshot: screenshot })
+    }
+
+    /// Save the transcript, giving up on saving after the first failure
+    fn save_session(&self, store: &mut Option<SessionStore>, messages: &[Message]) {
+        if let Some(session) = store
+            && let Err(e) = session.save(self.provider.model(), messages) {
+            eprintln!("Warning: Failed to save the session, it will not be saved again: {:#}", e);
+            *store = None;
+        }
+    }
+
+    /// Save the transcript with the part of the current response handled so far, so that the
+    /// actions already executed aren't repeated if the session is interrupted and resumed
+    fn save_step(&self, store: &mut Option<SessionStore>, messages: &[Message], step: &Step) {
+        if store.is_none() {
+            return;
+        }
+        let mut messages = messages.to_vec();
+        messages.push(Message { role: "assistant".to_string(), content: step.content.clone() });
+        if !step.tool_results.is_empty() {
+            messages.push(Message { role: "user".to_string(), content: step.tool_results.clone() });
+        }
+        self.save_session(store, &messages);
+    }
+
+    async fn stop_at_limit(
+        &self,
+        limit: Limit,
+        env: &dyn ComputerEnvironment,
+        final_message: Option<String>,
+        screenshot: image::RgbImage,
+        send_telemetry: bool
+    ) -> Result<RunOutcome> {
+        self.post_session_end(env, "limit", send_telemetry).await;
+        Ok(RunOutcome { stop_reason: StopReason::LimitReached(limit), final_message, output: None, final_screenshot: screenshot })
+    }
+
+    async fn post_session_end(&self, env: &dyn ComputerEnvironment, reason: &str, send_telemetry: bool) {
+        if send_telemetry {
+            post_telemetry(
+                &self.session_id,
+                &env.name(),
+                "session_end",
+                Some(reason),
+                Some(self.action_count.get())
+            ).await;
+        }
+    }
+}
+
+impl Agent {
+    /// Print and execute the response blocks as they arrive, returning the tool results.
+    /// Execution stops early if a limit is reached. `checkpoint` is called with the progress
+    /// after each block, to save the transcript.
+    async fn handle_events(
+        &self,
+        env: &mut dyn ComputerEnvironment,
+        events: &mut mpsc::UnboundedReceiver<ModelEvent>,
+        screenshot: &mut image::RgbImage,
+        scale: &mut f32,
+        started: Instant,
+        checkpoint: &mut dyn FnMut(&Step)
+    ) -> Result<Step> {
+        let mut step = Step::default();
+        let mut streaming_text = false;
+        let mut streaming_thinking = false;
+        let deadline = self.config.timeout.map(|timeout| started + Duration::from_secs(timeout));
+        loop {
+            // Waiting for the model can be cut short by the time limit, unlike an action
+            let event = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, events.recv()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        step.limit = Some(Limit::Time(deadline - started));
+                        return Ok(step);
+                    }
+                },
+                None => events.recv().await
+            };
+            let Some(event) = event else {
+                break;
+            };
+            let block = match event {
+                ModelEvent::ThinkingDelta(thinking) => {
+                    if !streaming_thinking {
+                        println!("\n{DIM}Thinking:{RESET}");
+                        streaming_thinking = true;
+                    }
+                    print!("{DIM}{}{RESET}", thinking);
+                    io::stdout().flush()?;
+                    continue;
+                }
+                ModelEvent::TextDelta(text) => {
+                    if !streaming_text {
+                        println!("\nUI-Act:");
+                        streaming_text = true;
+                    }
+                    print!("{}", text);
+                    io::stdout().flush()?;
+                    continue;
+                }
+                ModelEvent::Block(block) => {
+                    step.content.push(block.clone());
+                    block
+                }
+            };
+            match block {
+                ContentBlock::Text { text } => {
+                    if streaming_text {
+                        println!();
+                        streaming_text = false;
+                    } else {
+                        println!("\nUI-Act:\n{}", text);
+                    }
+                }
+                ContentBlock::ToolUse { name, input, id } => {
+                    if !input.is_object() {
+                        // E.g. arguments of an OpenAI compatible model that aren't valid JSON
+                        let input = input.as_str().map(str::to_string).unwrap_or_else(|| input.to_string());
+                        println!("  {} {}\n  Invalid tool input", name, input);
+                        step.tool_results.push(ContentBlock::ToolResult {
+                            content: vec![ContentBlock::Text { text: format!("The input of {} must be a JSON object: {}", name, input) }],
+                            tool_use_id: id,
+                            is_error: true
+                        });
+                    } else if let Some(schema) = self.output_schema.as_ref().filter(|_| name == RESULT_TOOL_NAME) {
+                        println!("  {} {}", name, input);
+                        let (text, is_error) = match schema.validate(&input) {
+                            Ok(()) => {
+                                println!("  Result accepted");
+                                step.output = Some(input);
+                                ("Result accepted".to_string(), false)
+                            }
+                            Err(errors) => {
+                                println!("  Result rejected:\n{}", errors);
+                                (format!("The result does not match the output schema:\n{}", errors), true)
+                            }
+                        };
+                        step.tool_results.push(ContentBlock::ToolResult {
+                            content: vec![ContentBlock::Text { text }],
+                            tool_use_id: id,
+                            is_error
+                        });
+                    } else if name == "computer" {
+                        let input = ToolInput::parse(input);
+                        println!("  {:?}", input);
+                        if let Some(limit) = self.exceeded_limit(started) {
+                            step.limit = Some(limit);
+                            return Ok(step);
+                        }
+                        self.action_count.set(self.action_count.get() + 1);
+                        // Saved before executing, so an interruption marks the action as such
+                        checkpoint(&step);
+                        let result = self.run_action(env, &id, &input, screenshot, scale).await?;
+                        step.tool_results.push(result);
+                    } else {
+                        // Every tool use needs a result, or the next request is rejected
+                        println!("  {} {}\n  Unknown tool {}", name, input, name);
+                        step.tool_results.push(ContentBlock::ToolResult {
+                            content: vec![ContentBlock::Text { text: format!("Unknown tool {}", name) }],
+                            tool_use_id: id,
+                            is_error: true
+                        });
+                    }
+                }
+                ContentBlock::Thinking { thinking, .. } => {
+                    if streaming_thinking {
+                        println!();
+                        streaming_thinking = false;
+                    } else {
+                        println!("\n{DIM}Thinking:\n{}{RESET}", thinking);
+                    }
+                }
+                ContentBlock::RedactedThinking { .. } => {
+                    println!("\n{DIM}Thinking: [redacted]{RESET}");
+                }
+                ContentBlock::Image { .. } => {
+                    println!("Image block in response, ignored");
+                }
+                _ => {
+                    return Err(anyhow::anyhow!("Unknown content block variant encountered"));
+                }
+            }
+            checkpoint(&step);
+        }
+        Ok(step)
+    }
+
+    /// Execute an action of the computer tool and return its result. Failed actions are
+    /// reported to the model, unless the environment itself is broken.
+    async fn run_action(
+        &self,
+        env: &mut dyn ComputerEnvironment,
+        id: &str,
+        input: &ToolInput,
+        screenshot: &mut image::RgbImage,
+        scale: &mut f32
+    ) -> Result<ContentBlock> {
+        if let ToolInput::Zoom { region } = input {
+            let cropped = zoom(screenshot, region);
+            return Ok(ContentBlock::ToolResult {
+                content: vec![ContentBlock::png_image(&cropped)?],
+                tool_use_id: id.to_string(),
+                is_error: false
+            });
+        }
+        let before = self.trace.borrow().is_some().then(|| screenshot.clone());
+        let output = match execute_action(env, input, *scale) {
+            Ok(output) => output,
+            Err(e) if is_fatal(&e) => return Err(e),
+            Err(e) => {
+                // Let the model see what went wrong and correct itself
+                println!("  Action failed: {:#}", e);
+                if let Some(before) = &before {
+                    self.record_trace(TracedAction {
+                        tool_use_id: id, input, scale: *scale, before, after: None,
+                        output: None, error: Some(format!("{:#}", e))
+                    });
+                }
+                return Ok(ContentBlock::ToolResult {
+                    content: vec![ContentBlock::Text { text: format!("{:#}", e) }],
+                    tool_use_id: id.to_string(),
+                    is_error: true
+                });
+            }
+        };
+
+        // Add a small delay after tool execution to allow UI to update
+        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+
+        // Send new screenshot as tool result
+        let action_scale = *scale;
+        (*screenshot, *scale) = self.take_screenshot(env)?;
+        if let Some(before) = &before {
+            self.record_trace(TracedAction {
+                tool_use_id: id, input, scale: action_scale, before, after: Some(screenshot),
+                output: output.clone(), error: None
+            });
+        }
+        let mut content: Vec<ContentBlock> = output.into_iter()
+            .map(|text| ContentBlock::Text { text })
+            .collect();
+        content.push(ContentBlock::png_image(screenshot)?);
+        Ok(ContentBlock::ToolResult {
+            content,
+            tool_use_id: id.to_string(),
+            is_error: false
+        })
+    }
+}
+
+/// The text blocks of a response, if it has any
+fn response_text(content: &[ContentBlock]) -> Option<String> {
+    let text = content.iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!text.is_empty()).then_some(text)
+}
+
+/// Continue a resumed conversation with the current screen of the new environment, and the
+/// new prompt if there is one. Actions that were interrupted are reported as failed.
+fn resume_conversation(messages: &mut Vec<Message>, prompt: &str, screenshot: &image::RgbImage) -> Result<()> {
+    let mut content = match messages.last() {
+        Some(message) if message.role == "user" => messages.pop().map(|m| m.content).unwrap_or_default(),
+        _ => vec![]
+    };
+    // The transcript is saved during a response, so its last actions may have no results
+    if let Some(message) = messages.last().filter(|m| m.role == "assistant") {
+        let interrupted = skipped_results(&message.content, &content, "The session was interrupted before this action completed");
+        content.splice(0..0, interrupted);
+    }
+    content.push(ContentBlock::Text {
+        text: "The session was resumed in a new environment, this is the current screen.".to_string()
+    });
+    content.push(ContentBlock::png_image(screenshot)?);
+    if !prompt.is_empty() {
+        content.push(ContentBlock::Text { text: prompt.to_string() });
     }
+    messages.push(Message { role: "user".to_string(), content });
+    Ok(())
+}
+
+/// Error results for the tool uses in `content` that have no result in `results`
+fn skipped_results(content: &[ContentBlock], results: &[ContentBlock], reason: &str) -> Vec<ContentBlock> {
+    content.iter()
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { id, .. } => Some(id),
+            _ => None
+        })
+        .filter(|id| !results.iter().any(|result| matches!(result, ContentBlock::ToolResult { tool_use_id, .. } if tool_use_id == *id)))
+        .map(|id| ContentBlock::ToolResult {
+            content: vec![ContentBlock::Text { text: reason.to_string() }],
+            tool_use_id: id.clone(),
+            is_error: true
+        })
+        .collect()
+}
+
+/// Whether an action error means the environment itself is broken, e.g. the X connection or an
+/// input device was lost. Other errors, like an unknown key name, are reported to the model.
+/// Errors of X requests, like BadWindow when one of several windows was closed, are not fatal.
+fn is_fatal(error: &anyhow::Error) -> bool {
+    use x11rb::errors::{ConnectionError, ReplyError};
+    error.chain().any(|cause| {
+        cause.is::<ConnectionError>()
+            || matches!(cause.downcast_ref::<ReplyError>(), Some(ReplyError::ConnectionError(_)))
+            || cause.is::<uinput::Error>()
+    })
+}
+
+/// Map a coordinate in screenshot space to environment space
+pub fn to_env_coordinate(coordinate: &[u32; 2], scale: f32) -> (u32, u32) {
+    let x = (coordinate[0] as f32 / scale).round() as u32;
+    let y = (coordinate[1] as f32 / scale).round() as u32;
+    (x, y)
+}
 
-    pub async fn get_response(&self, display_width_px: u32, display_height_px: u32, messages: &Vec<Message>) -> Result<reqwest::Response, reqwest::Error> {
-        let content = json!({
-            "model": self.model,
-            "max_tokens": 1024,
-            "tools": [{
-                "type": "computer_20251124",
-                "name": "computer",
-                "display_width_px": display_width_px,
-                "display_height_px": display_height_px,
-                "display_number": 1,
-                "enable_zoom": true
-            }],
-            "messages": messages
+/// Crop a region (x1, y1, x2, y2) of the screenshot, clamped to its bounds
+fn zoom(screenshot: &image::RgbImage, region: &[u32; 4]) -> image::RgbImage {
+    let x1 = region[0].min(screenshot.width());
+    let y1 = region[1].min(screenshot.height());
+    let x2 = region[2].min(screenshot.width());
+    let y2 = region[3].min(screenshot.height());
+    let w = x2.saturating_sub(x1);
+    let h = y2.saturating_sub(y1);
+    image::imageops::crop_imm(screenshot, x1, y1, w, h).to_image()
+}
+
+/// Perform an action in the environment. Coordinates are given in screenshot space
+/// and are mapped to the environment using `scale`.
+pub fn execute_action(env: &mut dyn ComputerEnvironment, input: &ToolInput, scale: f32) -> Result<Option<String>> {
+    match input {
+        ToolInput::MouseMove { coordinate } => {
+            let (x, y) = to_env_coordinate(coordinate, scale);
+            env.mouse_move(x, y)?;
+        }
+        ToolInput::CursorPosition => {
+            // Report in screenshot space, like the coordinates the model sends
+            let (x, y) = env.cursor_position()?;
+            let x = (x as f32 * scale).round() as u32;
+            let y = (y as f32 * scale).round() as u32;
+            return Ok(Some(format!("X={},Y={}", x, y)));
+        }
+        ToolInput::LeftClick { coordinate, text } => {
+            click(env, coordinate, text, scale, |env| env.left_click())?;
+        }
+        ToolInput::RightClick { coordinate, text } => {
+            click(env, coordinate, text, scale, |env| env.right_click())?;
+        }
+        ToolInput::MiddleClick { coordinate, text } => {
+            click(env, coordinate, text, scale, |env| env.middle_click())?;
+        }
+        ToolInput::DoubleClick { coordinate, text } => {
+            click(env, coordinate, text, scale, |env| env.double_click())?;
+        }
+        ToolInput::TripleClick { coordinate, text } => {
+            click(env, coordinate, text, scale, |env| env.triple_click())?;
+        }
+        ToolInput::Type { text } => {
+            env.type_text(text)?;
+        }
+        ToolInput::Key { text } => {
+            env.press_key(text)?;
+        }
+        ToolInput::Scroll { coordinate, scroll_direction, scroll_amount, text } => {
+            let (x, y) = to_env_coordinate(coordinate, scale);
+            env.mouse_move(x, y)?;
+            with_modifiers(env, text, |env| env.scroll(scroll_direction, *scroll_amount))?;
+        }
+        ToolInput::HoldKey { text, duration } => {
+            env.hold_key(text, seconds(*duration)?)?;
+        }
+        ToolInput::LeftMouseDown => {
+            env.left_mouse_down()?;
+        }
+        ToolInput::LeftMouseUp => {
+            env.left_mouse_up()?;
+        }
+        ToolInput::LeftClickDrag { start_coordinate, coordinate } => {
+            if let Some(start) = start_coordinate {
+                let (x, y) = to_env_coordinate(start, scale);
+                env.mouse_move(x, y)?;
+            }
+            let (x, y) = to_env_coordinate(coordinate, scale);
+            env.left_click_drag(x, y)?;
+        }
+        ToolInput::Wait { duration } => {
+            env.wait(seconds(*duration)?)?;
+        }
+        ToolInput::Screenshot | ToolInput::Zoom { .. } => {
+            // Do nothing, screenshot will be provided by the agent loop
+        }
+        ToolInput::Other(input) => {
+            return Err(anyhow::anyhow!("Unsupported action: {}", input));
+        }
+    }
+    Ok(None)
+}
+
+/// A duration given by the model in seconds
+fn seconds(duration: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(duration).map_err(|_| anyhow::anyhow!("Invalid duration: {}", duration))
+}
+
+/// Move to the coordinate, if any, and click while holding the modifier keys
+fn click(
+    env: &mut dyn ComputerEnvironment,
+    coordinate: &Option<[u32; 2]>,
+    modifiers: &Option<String>,
+    scale: f32,
+    click: impl FnOnce(&mut dyn ComputerEnvironment) -> Result<()>
+) -> Result<()> {
+    if let Some(coordinate) = coordinate {
+        let (x, y) = to_env_coordinate(coordinate, scale);
+        env.mouse_move(x, y)?;
+    }
+    with_modifiers(env, modifiers, click)
+}
+
+/// Run an action while holding the modifier keys, which are released even if the action fails
+fn with_modifiers(
+    env: &mut dyn ComputerEnvironment,
+    modifiers: &Option<String>,
+    action: impl FnOnce(&mut dyn ComputerEnvironment) -> Result<()>
+) -> Result<()> {
+    let Some(modifiers) = modifiers else {
+        return action(env);
+    };
+    env.key_down(modifiers)?;
+    let result = action(env);
+    env.key_up(modifiers)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::mock::{MockProvider, Request};
+    use crate::env::fake::{fill, Call, FakeEnvironment};
+    use base64::Engine as _;
+    use image::Rgb;
+    use std::path::Path;
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+
+    struct TestRun {
+        result: Result<RunOutcome>,
+        requests: Vec<Request>,
+        calls: Vec<Call>,
+    }
+
+    fn fixture(name: &str) -> MockProvider {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        MockProvider::load(&path).unwrap()
+    }
+
+    /// Run the agent on a 640x480 environment, with screenshots shrunk to half its size
+    async fn run_in(env: FakeEnvironment, provider: MockProvider, config: AgentConfig, output_schema: Option<OutputSchema>) -> TestRun {
+        let requests = provider.requests.clone();
+        let calls = env.calls.clone();
+        let mut env: Box<dyn ComputerEnvironment> = Box::new(env);
+        let provider = provider.with_max_screenshot_size(320, 240);
+        let mut agent = Agent::create(Box::new(provider), AgentConfig { non_interactive: true, ..config }, output_schema);
+        agent.save_session = false;
+        let result = agent.run(&mut env, "Close the dialog", vec![], false).await;
+        TestRun { result, requests: requests.take(), calls: calls.take() }
+    }
+
+    async fn run(provider: MockProvider, config: AgentConfig, output_schema: Option<OutputSchema>) -> TestRun {
+        run_in(FakeEnvironment::create(640, 480), provider, config, output_schema).await
+    }
+
+    fn tool_results(message: &Message) -> Vec<(&str, bool)> {
+        message.content.iter()
+            .filter_map(|block| match block {
+                ContentBlock::ToolResult { tool_use_id, is_error, .. } => Some((tool_use_id.as_str(), *is_error)),
+                _ => None
+            })
+            .collect()
+    }
+
+    fn tool_result_image(message: &Message) -> image::RgbImage {
+        let data = message.content.iter()
+            .find_map(|block| match block {
+                ContentBlock::ToolResult { content, .. } => content.iter().find_map(|block| match block {
+                    ContentBlock::Image { source: ImageSource::Base64 { data, .. } } => Some(data),
+                    _ => None
+                }),
+                _ => None
+            })
+            .unwrap();
+        let png = base64::engine::general_purpose::STANDARD.decode(data).unwrap();
+        image::load_from_memory(&png).unwrap().to_rgb8()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn executes_actions_in_environment_coordinates() {
+        let run = run(fixture("click.json"), AgentConfig::default(), None).await;
+        let outcome = run.result.unwrap();
+        assert_eq!(outcome.stop_reason, StopReason::Completed);
+        assert_eq!(outcome.final_message.as_deref(), Some("The dialog is closed."));
+        assert_eq!(run.calls, [Call::MouseMove { x: 200, y: 100 }, Call::LeftClick { x: 200, y: 100 }]);
+
+        assert_eq!(run.requests.len(), 2);
+        assert_eq!(run.requests[0].display_size, (320, 240));
+        let last = run.requests[1].messages.last().unwrap();
+        assert_eq!(last.role, "user");
+        assert_eq!(tool_results(last), [("toolu_01", false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_screenshot_of_the_changed_screen() {
+        // Clicking the button at (200, 100) closes the dialog, which turns the screen red
+        let env = FakeEnvironment::create(640, 480)
+            .on_click((180, 80, 40, 40), |screen| fill(screen, (0, 0, 640, 480), RED));
+        let run = run_in(env, fixture("click.json"), AgentConfig::default(), None).await;
+        assert!(run.result.is_ok());
+        let screenshot = tool_result_image(run.requests[1].messages.last().unwrap());
+        assert_eq!(screenshot.dimensions(), (320, 240));
+        assert_eq!(*screenshot.get_pixel(0, 0), RED);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_rendered_system_prompt() {
+        let config = AgentConfig { system_prompt: Some("The {{environment}} is {{screen_width}}x{{screen_height}}".to_string()), ..Default::default() };
+        let run = run(fixture("click.json"), config, None).await;
+        assert_eq!(run.requests[0].system.as_deref(), Some("The fake is 640x480"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_failed_actions_to_the_model() {
+        let run = run(fixture("action_error.json"), AgentConfig::default(), None).await;
+        assert_eq!(run.result.unwrap().stop_reason, StopReason::Completed);
+        assert_eq!(run.calls, [Call::PressKey("Return".to_string())]);
+        assert_eq!(tool_results(run.requests[1].messages.last().unwrap()), [("toolu_01", true)]);
+        assert_eq!(tool_results(run.requests[2].messages.last().unwrap()), [("toolu_02", false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accepts_results_with_an_action_property() {
+        // A result that looks like a computer action must be taken as is
+        let schema = OutputSchema::from_value(serde_json::json!({
+            "type": "object",
+            "properties": { "action": { "type": "string" }, "note": { "type": "string" } },
+            "required": ["action", "note"]
+        })).unwrap();
+        let result = serde_json::json!({ "action": "screenshot", "note": "The dialog shows an error" });
+        let provider = MockProvider::create(serde_json::from_value(serde_json::json!([
+            { "content": [{ "type": "tool_use", "id": "toolu_01", "name": RESULT_TOOL_NAME, "input": result }] }
+        ])).unwrap());
+        let run = run(provider, AgentConfig::default(), Some(schema)).await;
+        assert_eq!(run.result.unwrap().output, Some(result));
+        assert!(run.calls.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_unknown_tools_to_the_model() {
+        let run = run(fixture("unknown_tool.json"), AgentConfig::default(), None).await;
+        assert_eq!(run.result.unwrap().stop_reason, StopReason::Completed);
+        assert!(run.calls.is_empty());
+        assert_eq!(tool_results(run.requests[1].messages.last().unwrap()), [("toolu_01", true)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_invalid_tool_input_to_the_model() {
+        let provider = MockProvider::create(serde_json::from_value(serde_json::json!([
+            { "content": [{ "type": "tool_use", "id": "toolu_01", "name": "computer", "input": "{\"action\":\"left_cl" }] },
+            { "content": [{ "type": "text", "text": "Done." }] }
+        ])).unwrap());
+        let run = run(provider, AgentConfig::default(), None).await;
+        assert_eq!(run.result.unwrap().stop_reason, StopReason::Completed);
+        assert!(run.calls.is_empty());
+        assert_eq!(tool_results(run.requests[1].messages.last().unwrap()), [("toolu_01", true)]);
+    }
+
+    #[test]
+    fn drags_from_the_start_coordinate() {
+        let mut env = FakeEnvironment::create(640, 480);
+        let calls = env.calls.clone();
+        let input: ToolInput = serde_json::from_value(serde_json::json!({
+            "action": "left_click_drag", "start_coordinate": [10, 20], "coordinate": [100, 50]
+        })).unwrap();
+        execute_action(&mut env, &input, 0.5).unwrap();
+        assert_eq!(*calls.borrow(), [Call::MouseMove { x: 20, y: 40 }, Call::LeftClickDrag { x: 200, y: 100 }]);
+    }
+
+    #[test]
+    fn accepts_fractional_durations() {
+        let mut env = FakeEnvironment::create(640, 480);
+        let calls = env.calls.clone();
+        let input: ToolInput = serde_json::from_value(serde_json::json!({ "action": "wait", "duration": 0.5 })).unwrap();
+        execute_action(&mut env, &input, 1.0).unwrap();
+        let input: ToolInput = serde_json::from_value(serde_json::json!({ "action": "hold_key", "text": "shift", "duration": -1 })).unwrap();
+        assert!(execute_action(&mut env, &input, 1.0).is_err());
+        assert_eq!(*calls.borrow(), [Call::Wait(Duration::from_millis(500))]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_environment_ends_the_session() {
+        let env = FakeEnvironment::create(640, 480).disconnect_after(1);
+        let run = run_in(env, fixture("two_actions.json"), AgentConfig::default(), None).await;
+        let error = run.result.err().unwrap();
+        assert!(matches!(error.downcast_ref::<AgentError>(), Some(AgentError::Environment(_))));
+        assert_eq!(run.calls, [Call::TypeText("hello".to_string())]);
+    }
+
+    #[test]
+    fn only_lost_connections_and_devices_are_fatal() {
+        use x11rb::errors::{ConnectionError, ReplyError};
+        let lost = || ConnectionError::IoError(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Broken pipe"));
+        assert!(is_fatal(&anyhow::Error::new(lost()).context("Failed to click")));
+        assert!(is_fatal(&ReplyError::ConnectionError(lost()).into()));
+        assert!(is_fatal(&uinput::Error::NotFound.into()));
+
+        let bad_window = ReplyError::X11Error(x11rb::x11_utils::X11Error {
+            error_kind: x11rb::protocol::ErrorKind::Window,
+            error_code: 3,
+            sequence: 1,
+            bad_value: 0x400001,
+            minor_opcode: 0,
+            major_opcode: 14,
+            extension_name: None,
+            request_name: Some("GetGeometry"),
         });
+        assert!(!is_fatal(&anyhow::Error::new(bad_window).context("Failed to get the geometry of window 4194305")));
+        assert!(!is_fatal(&std::io::Error::new(std::io::ErrorKind::StorageFull, "No space left on device").into()));
+        assert!(!is_fatal(&anyhow::anyhow!("Unknown key: notakey")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn api_failure_ends_non_interactive_session() {
+        let run = run(fixture("api_error.json"), AgentConfig::default(), None).await;
+        let error = run.result.err().unwrap();
+        assert!(matches!(error.downcast_ref::<AgentError>(), Some(AgentError::Api(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_at_action_limit() {
+        let config = AgentConfig { max_actions: Some(1), ..Default::default() };
+        let run = run(fixture("two_actions.json"), config, None).await;
+        assert_eq!(run.result.unwrap().stop_reason, StopReason::LimitReached(Limit::Actions(1)));
+        assert_eq!(run.calls, [Call::TypeText("hello".to_string())]);
+        assert_eq!(run.requests.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_at_timeout_between_actions() {
+        // The first action and the delay after it take the whole second
+        let config = AgentConfig { timeout: Some(1), ..Default::default() };
+        let run = run(fixture("two_actions.json"), config, None).await;
+        assert_eq!(run.result.unwrap().stop_reason, StopReason::LimitReached(Limit::Time(Duration::from_secs(1))));
+        assert_eq!(run.calls, [Call::TypeText("hello".to_string())]);
+        assert_eq!(run.requests.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_at_timeout_while_waiting_for_the_model() {
+        let provider = MockProvider::create(serde_json::from_value(serde_json::json!([
+            { "content": [{ "type": "tool_use", "id": "toolu_01", "name": "computer", "input": { "action": "type", "text": "hello" } }], "delay_ms": 5000 }
+        ])).unwrap());
+        let config = AgentConfig { timeout: Some(1), ..Default::default() };
+        let run = run(provider, config, None).await;
+        assert_eq!(run.result.unwrap().stop_reason, StopReason::LimitReached(Limit::Time(Duration::from_secs(1))));
+        assert!(run.calls.is_empty());
+    }
+
+    #[test]
+    fn marks_actions_without_results_as_skipped() {
+        let content = [ContentBlock::Text { text: "Typing".to_string() }, tool_use("toolu_01"), tool_use("toolu_02")];
+        let results = [tool_result("toolu_01")];
+        let message = Message { role: "user".to_string(), content: skipped_results(&content, &results, "Not executed") };
+        assert_eq!(tool_results(&message), [("toolu_02", true)]);
+    }
+
+    fn tool_use(id: &str) -> ContentBlock {
+        ContentBlock::ToolUse { id: id.to_string(), name: "computer".to_string(), input: serde_json::json!({ "action": "type", "text": "hello" }) }
+    }
+
+    fn tool_result(id: &str) -> ContentBlock {
+        ContentBlock::ToolResult { content: vec![], tool_use_id: id.to_string(), is_error: false }
+    }
+
+    fn resume(mut messages: Vec<Message>, prompt: &str) -> Vec<Message> {
+        messages.insert(0, Message { role: "user".to_string(), content: vec![ContentBlock::Text { text: "Close the dialog".to_string() }] });
+        resume_conversation(&mut messages, prompt, &image::RgbImage::new(32, 24)).unwrap();
+        messages
+    }
+
+    #[test]
+    fn resumes_with_interrupted_actions_marked_as_failed() {
+        // Saved while executing the second action of a response
+        let messages = resume(vec![
+            Message { role: "assistant".to_string(), content: vec![tool_use("toolu_01"), tool_use("toolu_02")] },
+            Message { role: "user".to_string(), content: vec![tool_result("toolu_01")] },
+        ], "");
+        assert_eq!(messages.len(), 3);
+        let last = messages.last().unwrap();
+        assert_eq!(tool_results(last), [("toolu_02", true), ("toolu_01", false)]);
+        assert!(matches!(last.content.last(), Some(ContentBlock::Image { .. })));
+
+        // Saved before any action of the response had a result
+        let messages = resume(vec![
+            Message { role: "assistant".to_string(), content: vec![ContentBlock::Text { text: "Typing".to_string() }, tool_use("toolu_01")] },
+        ], "");
+        assert_eq!(messages.len(), 3);
+        assert_eq!(tool_results(messages.last().unwrap()), [("toolu_01", true)]);
+    }
+
+    #[test]
+    fn resumes_a_completed_step_with_the_new_prompt() {
+        let messages = resume(vec![
+            Message { role: "assistant".to_string(), content: vec![tool_use("toolu_01")] },
+            Message { role: "user".to_string(), content: vec![tool_result("toolu_01")] },
+        ], "Now open the settings");
+        assert_eq!(messages.len(), 3);
+        let last = messages.last().unwrap();
+        assert_eq!(tool_results(last), [("toolu_01", false)]);
+        assert!(matches!(last.content.last(), Some(ContentBlock::Text { text }) if text == "Now open the settings"));
+
+        // A finished conversation continues with a new user message
+        let messages = resume(vec![
+            Message { role: "assistant".to_string(), content: vec![ContentBlock::Text { text: "Done.".to_string() }] },
+        ], "Now open the settings");
+        assert_eq!(messages.len(), 3);
+        assert!(tool_results(messages.last().unwrap()).is_empty());
+    }
 
-        self.client.post("https://api.anthropic.com/v1/messages")
-            .header("content-type", "application/json")
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("anthropic-beta", "computer-use-2025-11-24")
-            .json(&content)
-            .send()
-            .await
+    #[tokio::test(start_paused = true)]
+    async fn accepts_only_results_matching_the_schema() {
+        let schema = OutputSchema::from_value(serde_json::json!({
+            "type": "object",
+            "properties": { "count": { "type": "integer" } },
+            "required": ["count"]
+        })).unwrap();
+        let run = run(fixture("submit_result.json"), AgentConfig::default(), Some(schema)).await;
+        let outcome = run.result.unwrap();
+        assert_eq!(outcome.output, Some(serde_json::json!({ "count": 3 })));
+        assert_eq!(run.requests[0].tools, [RESULT_TOOL_NAME]);
+        assert_eq!(tool_results(run.requests[1].messages.last().unwrap()), [("toolu_01", true)]);
     }
 }
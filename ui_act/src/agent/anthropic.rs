@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use serde::Deserialize;
+use reqwest::Response;
+use reqwest::header::HeaderMap;
+use crate::agent::{ContentBlock, Message, ModelEvent, ModelEvents, ModelProvider, ModelResponse, ToolDefinition, Usage};
+use crate::agent::retry::{RetryPolicy, send_with_retry};
+use crate::config::{AgentConfig, ClientConfig};
+
+/// Server-sent events of the streaming Messages API
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum StreamEvent {
+    #[serde(rename = "message_start")]
+    MessageStart { message: MessageStart },
+    #[serde(rename = "message_delta")]
+    MessageDelta { usage: Usage },
+    #[serde(rename = "content_block_start")]
+    ContentBlockStart { index: usize, content_block: Value },
+    #[serde(rename = "content_block_delta")]
+    ContentBlockDelta { index: usize, delta: Delta },
+    #[serde(rename = "content_block_stop")]
+    ContentBlockStop { index: usize },
+    #[serde(rename = "message_stop")]
+    MessageStop,
+    #[serde(rename = "error")]
+    Error { error: Value },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageStart {
+    usage: Usage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum Delta {
+    #[serde(rename = "text_delta")]
+    Text { text: String },
+    #[serde(rename = "input_json_delta")]
+    InputJson { partial_json: String },
+    #[serde(rename = "thinking_delta")]
+    Thinking { thinking: String },
+    #[serde(rename = "signature_delta")]
+    Signature { signature: String },
+    #[serde(other)]
+    Other,
+}
+
+/// An `error` event in the stream, e.g. when the API became overloaded while responding
+#[derive(Debug, thiserror::Error)]
+#[error("Anthropic API stream failed: {0}")]
+struct StreamErrorEvent(Value);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Anthropic API stream ended before the message was complete")]
+struct IncompleteStream;
+
+/// Whether a stream failed because of overload or a lost connection, which is worth retrying
+fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<reqwest::Error>()
+            || cause.is::<IncompleteStream>()
+            || cause.downcast_ref::<StreamErrorEvent>().is_some_and(|event| event.0["type"] == "overloaded_error")
+    })
+}
+
+/// A content block being assembled from stream deltas
+struct PartialBlock {
+    block: Value,
+    input_json: String,
+}
+
+/// Assembles content blocks from the event stream, forwarding text deltas and completed blocks
+#[derive(Default)]
+struct StreamAssembler {
+    partial: Vec<Option<PartialBlock>>,
+    content: Vec<ContentBlock>,
+    usage: Usage,
+    /// Whether `message_stop` was received, after which every block is complete
+    stopped: bool,
+    /// Whether any delta or block was forwarded, after which the response can't be restarted
+    forwarded: bool,
+}
+
+impl StreamAssembler {
+    fn handle(&mut self, event: StreamEvent, events: &ModelEvents) -> Result<()> {
+        match event {
+            StreamEvent::MessageStart { message } => {
+                self.usage = message.usage;
+            }
+            StreamEvent::MessageDelta { usage } => {
+                // Counts in the delta are cumulative, input counts may be left out
+                self.usage.output_tokens = usage.output_tokens;
+                if usage.input_tokens > 0 {
+                    self.usage.input_tokens = usage.input_tokens;
+                }
+                if usage.cache_creation_input_tokens > 0 {
+                    self.usage.cache_creation_input_tokens = usage.cache_creation_input_tokens;
+                }
+                if usage.cache_read_input_tokens > 0 {
+                    self.usage.cache_read_input_tokens = usage.cache_read_input_tokens;
+                }
+            }
+            StreamEvent::ContentBlockStart { index, content_block } => {
+                if self.partial.len() <= index {
+                    self.partial.resize_with(index + 1, || None);
+                }
+                self.partial[index] = Some(PartialBlock { block: content_block, input_json: String::new() });
+            }
+            StreamEvent::ContentBlockDelta { index, delta } => {
+                let partial = self.partial.get_mut(index).and_then(|p| p.as_mut())
+                    .ok_or_else(|| anyhow!("Delta for unknown content block {}", index))?;
+                match delta {
+                    Delta::Text { text } => {
+                        let current = partial.block["text"].as_str().unwrap_or_default();
+                        partial.block["text"] = json!(format!("{current}{text}"));
+                        let _ = events.send(ModelEvent::TextDelta(text));
+                        self.forwarded = true;
+                    }
+                    Delta::InputJson { partial_json } => partial.input_json.push_str(&partial_json),
+                    Delta::Thinking { thinking } => {
+                        let current = partial.block["thinking"].as_str().unwrap_or_default();
+                        partial.block["thinking"] = json!(format!("{current}{thinking}"));
+                        let _ = events.send(ModelEvent::ThinkingDelta(thinking));
+                        self.forwarded = true;
+                    }
+                    Delta::Signature { signature } => {
+                        let current = partial.block["signature"].as_str().unwrap_or_default();
+                        partial.block["signature"] = json!(format!("{current}{signature}"));
+                    }
+                    Delta::Other => {}
+                }
+            }
+            StreamEvent::ContentBlockStop { index } => {
+                let mut partial = self.partial.get_mut(index).and_then(|p| p.take())
+                    .ok_or_else(|| anyhow!("Stop for unknown content block {}", index))?;
+                if !partial.input_json.is_empty() {
+                    partial.block["input"] = serde_json::from_str(&partial.input_json)?;
+                }
+                let block: ContentBlock = match serde_json::from_value(partial.block.clone()) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        eprintln!("Failed to parse content block: {e}\nContent block:\n{}", partial.block);
+                        return Err(e.into());
+                    }
+                };
+                let _ = events.send(ModelEvent::Block(block.clone()));
+                self.forwarded = true;
+                self.content.push(block);
+            }
+            StreamEvent::MessageStop => {
+                self.stopped = true;
+            }
+            StreamEvent::Error { error } => {
+                return Err(StreamErrorEvent(error).into());
+            }
+            StreamEvent::Other => {}
+        }
+        Ok(())
+    }
+}
+
+/// Read the server-sent events of a response into `assembler`, until the message is complete
+async fn read_stream(resp: &mut Response, assembler: &mut StreamAssembler, events: &ModelEvents) -> Result<()> {
+    // Events are separated by a blank line, with the JSON payload on the data line
+    let mut buffer: Vec<u8> = vec![];
+    while let Some(chunk) = resp.chunk().await? {
+        buffer.extend(chunk.iter().filter(|b| **b != b'\r'));
+        while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = buffer.drain(..end + 2).collect();
+            let event = String::from_utf8_lossy(&event);
+            for data in event.lines().filter_map(|line| line.strip_prefix("data:")) {
+                let event: StreamEvent = match serde_json::from_str(data.trim()) {
+                    Ok(val) => val,
+                    Err(e) => {
+                        eprintln!("Failed to parse stream event: {e}\nEvent:\n{data}");
+                        return Err(e.into());
+                    }
+                };
+                assembler.handle(event, events)?;
+            }
+        }
+    }
+    if !assembler.stopped {
+        return Err(IncompleteStream.into());
+    }
+    Ok(())
+}
+
+/// Serialize the messages with cache breakpoints at the end of the two most recent user turns.
+/// The latest one writes the conversation so far to the prompt cache, and the previous one
+/// reads the prefix that was written in the last step.
+fn with_cache_breakpoints(messages: &[Message]) -> Result<Value> {
+    let mut messages = serde_json::to_value(messages)?;
+    if let Some(messages) = messages.as_array_mut() {
+        messages.iter_mut().rev()
+            .filter(|m| m["role"] == "user")
+            .take(2)
+            .filter_map(|m| m["content"].as_array_mut()?.last_mut())
+            .for_each(|block| block["cache_control"] = json!({ "type": "ephemeral" }));
+    }
+    Ok(messages)
+}
+
+const ANTHROPIC_DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
+const ANTHROPIC_MAX_WIDTH: u32 = 1024;
+const ANTHROPIC_MAX_HEIGHT: u32 = 768;
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+const MIN_THINKING_BUDGET: u32 = 1024;
+
+pub struct AnthropicProvider {
+    client: reqwest::Client,
+    api_key: String,
+    model: String,
+    base_url: String,
+    headers: HeaderMap,
+    retry: RetryPolicy,
+    max_tokens: u32,
+    thinking_budget: Option<u32>,
+}
+
+impl AnthropicProvider {
+    pub fn create(model: String, client_config: &ClientConfig, agent_config: &AgentConfig) -> Result<Self> {
+        let thinking_budget = agent_config.thinking_budget;
+        // The thinking budget counts towards max_tokens, so leave room for the answer by default
+        let max_tokens = agent_config.max_tokens
+            .unwrap_or(thinking_budget.unwrap_or(0) + DEFAULT_MAX_TOKENS);
+        if let Some(budget) = thinking_budget {
+            if budget < MIN_THINKING_BUDGET {
+                return Err(anyhow!("Thinking budget must be at least {} tokens", MIN_THINKING_BUDGET));
+            }
+            if budget >= max_tokens {
+                return Err(anyhow!("Thinking budget ({}) must be less than max tokens ({})", budget, max_tokens));
+            }
+        }
+        let client = client_config.build_client()?;
+        let api_key = std::env::var("ANTHROPIC_API_KEY")?;
+        let base_url = client_config.base_url.as_deref().unwrap_or(ANTHROPIC_DEFAULT_BASE_URL);
+        Ok(AnthropicProvider {
+            client,
+            api_key,
+            model,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers: client_config.header_map()?,
+            retry: client_config.retry_policy(),
+            max_tokens,
+            thinking_budget
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl ModelProvider for AnthropicProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn max_screenshot_size(&self) -> (u32, u32) {
+        (ANTHROPIC_MAX_WIDTH, ANTHROPIC_MAX_HEIGHT)
+    }
+
+    async fn get_response(&self, system: Option<&str>, tools: &[ToolDefinition], display_width_px: u32, display_height_px: u32, messages: &[Message], events: ModelEvents) -> Result<ModelResponse> {
+        let mut tool_definitions = vec![json!({
+            "type": "computer_20251124",
+            "name": "computer",
+            "display_width_px": display_width_px,
+            "display_height_px": display_height_px,
+            "display_number": 1,
+            "enable_zoom": true
+        })];
+        tool_definitions.extend(tools.iter().map(|tool| json!({
+            "name": tool.name,
+            "description": tool.description,
+            "input_schema": tool.input_schema
+        })));
+        let mut content = json!({
+            "model": self.model,
+            "max_tokens": self.max_tokens,
+            "tools": tool_definitions,
+            "messages": with_cache_breakpoints(messages)?,
+            "stream": true
+        });
+        if let Some(system) = system {
+            content["system"] = json!(system);
+        }
+        let mut beta = "computer-use-2025-11-24".to_string();
+        if let Some(budget) = self.thinking_budget {
+            content["thinking"] = json!({ "type": "enabled", "budget_tokens": budget });
+            // Lets the model think between tool calls, not only at the start of a turn
+            beta.push_str(",interleaved-thinking-2025-05-14");
+        }
+
+        let request = self.client.post(format!("{}/v1/messages", self.base_url))
+            .header("content-type", "application/json")
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .header("anthropic-beta", beta)
+            .headers(self.headers.clone())
+            .json(&content);
+        let mut attempt = 0;
+        loop {
+            let mut resp = send_with_retry(&request, &self.retry).await?;
+            if !resp.status().is_success() {
+                return Err(anyhow!("Anthropic API request failed with status: {}\n{}", resp.status(), resp.text().await?));
+            }
+            let mut assembler = StreamAssembler::default();
+            match read_stream(&mut resp, &mut assembler, &events).await {
+                Ok(()) => return Ok(ModelResponse { content: assembler.content, usage: assembler.usage }),
+                // Streamed text has been printed and completed blocks may have been executed, so
+                // only retry before anything was forwarded
+                Err(e) if is_transient(&e) && !assembler.forwarded => {
+                    if !self.retry.wait_to_retry(attempt, &format!("{:#}", e), None).await {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn feed(assembler: &mut StreamAssembler, events: &[Value]) -> Result<()> {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        for event in events {
+            assembler.handle(serde_json::from_value(event.clone())?, &tx)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn assembles_tool_use_from_input_deltas() {
+        let mut assembler = StreamAssembler::default();
+        feed(&mut assembler, &[
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "tool_use", "id": "toolu_01", "name": "computer", "input": {} } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "input_json_delta", "partial_json": "{\"action\": \"key\", " } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "input_json_delta", "partial_json": "\"text\": \"Return\"}" } }),
+            json!({ "type": "content_block_stop", "index": 0 }),
+        ]).unwrap();
+        assert!(matches!(&assembler.content[..], [ContentBlock::ToolUse { id, .. }] if id == "toolu_01"));
+        assert!(assembler.forwarded);
+        assert!(!assembler.stopped);
+        feed(&mut assembler, &[json!({ "type": "message_stop" })]).unwrap();
+        assert!(assembler.stopped);
+    }
+
+    #[test]
+    fn marks_streamed_text_as_forwarded() {
+        // Input JSON isn't forwarded until the block is complete
+        let mut assembler = StreamAssembler::default();
+        feed(&mut assembler, &[
+            json!({ "type": "message_start", "message": { "usage": { "input_tokens": 10 } } }),
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "tool_use", "id": "toolu_01", "name": "computer", "input": {} } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "input_json_delta", "partial_json": "{\"action\": " } }),
+        ]).unwrap();
+        assert!(!assembler.forwarded);
+
+        let mut assembler = StreamAssembler::default();
+        feed(&mut assembler, &[
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "Clicking" } }),
+        ]).unwrap();
+        assert!(assembler.forwarded);
+        assert!(assembler.content.is_empty());
+    }
+
+    #[test]
+    fn retries_only_overload_and_lost_connections() {
+        let mut assembler = StreamAssembler::default();
+        let overloaded = feed(&mut assembler, &[json!({ "type": "error", "error": { "type": "overloaded_error", "message": "Overloaded" } })]);
+        assert!(is_transient(&overloaded.unwrap_err()));
+        let invalid = feed(&mut assembler, &[json!({ "type": "error", "error": { "type": "invalid_request_error", "message": "Bad" } })]);
+        assert!(!is_transient(&invalid.unwrap_err()));
+        assert!(is_transient(&IncompleteStream.into()));
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use crate::agent::{ContentBlock, Message};
+
+/// Replace all but the `keep` most recent images in the conversation with a short text
+/// placeholder. Tool results themselves are kept, so every `tool_use` block still has a
+/// matching `tool_result`. Images are removed in multiples of `batch_size`, so that the
+/// conversation prefix stays unchanged (and cached) between most steps.
+pub fn prune_screenshots(messages: &mut [Message], keep: usize, batch_size: usize) {
+    let total = messages.iter()
+        .flat_map(|m| m.content.iter())
+        .map(|block| match block {
+            ContentBlock::Image { .. } => 1,
+            ContentBlock::ToolResult { content, .. } => content.iter().filter(|b| matches!(b, ContentBlock::Image { .. })).count(),
+            _ => 0
+        })
+        .sum::<usize>();
+    let to_remove = total.saturating_sub(keep);
+    let keep = total - (to_remove - to_remove % batch_size.max(1));
+
+    // Describe each action so the placeholder tells the model what the screenshot followed
+    let actions: HashMap<String, String> = messages.iter()
+        .flat_map(|m| m.content.iter())
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { id, input, .. } => Some((id.clone(), input.to_string())),
+            _ => None
+        })
+        .collect();
+
+    let mut seen = 0;
+    for message in messages.iter_mut().rev() {
+        for block in message.content.iter_mut().rev() {
+            match block {
+                ContentBlock::Image { .. } => {
+                    seen += 1;
+                    if seen > keep {
+                        *block = placeholder(None);
+                    }
+                }
+                ContentBlock::ToolResult { content, tool_use_id, .. } => {
+                    for inner in content.iter_mut().rev() {
+                        if let ContentBlock::Image { .. } = inner {
+                            seen += 1;
+                            if seen > keep {
+                                *inner = placeholder(actions.get(tool_use_id));
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn placeholder(action: Option<&String>) -> ContentBlock {
+    let text = match action {
+        Some(action) => format!("[Screenshot after {} omitted to save context]", action),
+        None => "[Screenshot omitted to save context]".to_string()
+    };
+    ContentBlock::Text { text }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::ImageSource;
+
+    fn image() -> ContentBlock {
+        ContentBlock::Image { source: ImageSource::Base64 { media_type: "image/png".to_string(), data: String::new() } }
+    }
+
+    /// A task with its first screenshot, followed by `actions` key presses with a screenshot each
+    fn conversation(actions: usize) -> Vec<Message> {
+        let mut messages = vec![Message { role: "user".to_string(), content: vec![ContentBlock::Text { text: "Task".to_string() }, image()] }];
+        for i in 0..actions {
+            let id = format!("toolu_{}", i);
+            messages.push(Message { role: "assistant".to_string(), content: vec![
+                ContentBlock::ToolUse { id: id.clone(), name: "computer".to_string(), input: serde_json::json!({ "action": "key", "text": "Return" }) }
+            ]});
+            messages.push(Message { role: "user".to_string(), content: vec![
+                ContentBlock::ToolResult { content: vec![image()], tool_use_id: id, is_error: false }
+            ]});
+        }
+        messages
+    }
+
+    /// Whether each screenshot is still there, oldest first, and the placeholder texts
+    fn screenshots(messages: &[Message]) -> (Vec<bool>, Vec<String>) {
+        let mut kept = vec![];
+        let mut placeholders = vec![];
+        let blocks = messages.iter().flat_map(|m| m.content.iter()).flat_map(|block| match block {
+            ContentBlock::ToolResult { content, .. } => content.iter().collect(),
+            block => vec![block],
+        });
+        for block in blocks {
+            match block {
+                ContentBlock::Image { .. } => kept.push(true),
+                ContentBlock::Text { text } if text.starts_with("[Screenshot") => {
+                    kept.push(false);
+                    placeholders.push(text.clone());
+                }
+                _ => {}
+            }
+        }
+        (kept, placeholders)
+    }
+
+    #[test]
+    fn removes_screenshots_in_whole_batches() {
+        // 7 screenshots and 3 to keep leaves 4 to remove, exactly two batches
+        let mut messages = conversation(6);
+        prune_screenshots(&mut messages, 3, 2);
+        assert_eq!(screenshots(&messages).0, [false, false, false, false, true, true, true]);
+
+        // 6 screenshots leaves 3 to remove, of which only one whole batch is removed
+        let mut messages = conversation(5);
+        prune_screenshots(&mut messages, 3, 2);
+        assert_eq!(screenshots(&messages).0, [false, false, true, true, true, true]);
+    }
+
+    #[test]
+    fn describes_the_action_of_removed_tool_result_screenshots() {
+        let mut messages = conversation(2);
+        prune_screenshots(&mut messages, 1, 1);
+        assert_eq!(screenshots(&messages).1, [
+            "[Screenshot omitted to save context]",
+            r#"[Screenshot after {"action":"key","text":"Return"} omitted to save context]"#,
+        ]);
+        // Every tool use still has its result
+        assert!(matches!(&messages[2].content[..], [ContentBlock::ToolResult { content, .. }] if matches!(content[..], [ContentBlock::Text { .. }])));
+    }
+
+    #[test]
+    fn keeps_everything_within_the_limit() {
+        let mut messages = conversation(2);
+        prune_screenshots(&mut messages, 3, 5);
+        assert_eq!(screenshots(&messages).0, [true, true, true]);
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::rc::Rc;
+use crate::agent::{ContentBlock, Message, ModelEvent, ModelEvents, ModelProvider, ModelResponse, ToolDefinition, Usage};
+
+/// A canned reply of the mock provider, either content blocks or a failed request
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ScriptedResponse {
+    Error { error: String },
+    Content {
+        content: Vec<ContentBlock>,
+        #[serde(default)]
+        usage: Usage,
+        /// Time the model takes before the response starts
+        #[serde(default)]
+        delay_ms: u64,
+    },
+}
+
+/// A model request as received by the mock provider
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub system: Option<String>,
+    pub tools: Vec<String>,
+    pub display_size: (u32, u32),
+    pub messages: Vec<Message>,
+}
+
+/// A provider that replays scripted responses in order instead of calling a model API, and
+/// records the requests it receives in `requests`. Fails once the script is exhausted.
+pub struct MockProvider {
+    model: String,
+    max_screenshot_size: (u32, u32),
+    responses: RefCell<VecDeque<ScriptedResponse>>,
+    pub requests: Rc<RefCell<Vec<Request>>>,
+}
+
+impl MockProvider {
+    pub fn create(responses: Vec<ScriptedResponse>) -> Self {
+        MockProvider {
+            model: "mock".to_string(),
+            max_screenshot_size: (1024, 768),
+            responses: RefCell::new(responses.into()),
+            requests: Rc::new(RefCell::new(vec![])),
+        }
+    }
+
+    /// Load the script from a JSON fixture with an array of responses
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read fixture {}", path.display()))?;
+        let responses = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse fixture {}", path.display()))?;
+        Ok(Self::create(responses))
+    }
+
+    pub fn with_max_screenshot_size(mut self, width: u32, height: u32) -> Self {
+        self.max_screenshot_size = (width, height);
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl ModelProvider for MockProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn max_screenshot_size(&self) -> (u32, u32) {
+        self.max_screenshot_size
+    }
+
+    async fn get_response(&self, system: Option<&str>, tools: &[ToolDefinition], display_width_px: u32, display_height_px: u32, messages: &[Message], events: ModelEvents) -> Result<ModelResponse> {
+        self.requests.borrow_mut().push(Request {
+            system: system.map(str::to_string),
+            tools: tools.iter().map(|tool| tool.name.clone()).collect(),
+            display_size: (display_width_px, display_height_px),
+            messages: messages.to_vec(),
+        });
+        let response = self.responses.borrow_mut().pop_front()
+            .ok_or_else(|| anyhow!("Mock provider script exhausted"))?;
+        match response {
+            ScriptedResponse::Error { error } => Err(anyhow!(error)),
+            ScriptedResponse::Content { content, usage, delay_ms } => {
+                tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+                for block in &content {
+                    let _ = events.send(ModelEvent::Block(block.clone()));
+                }
+                Ok(ModelResponse { content, usage })
+            }
+        }
+    }
+}
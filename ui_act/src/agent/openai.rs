@@ -0,0 +1,445 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use serde::Deserialize;
+use uuid::Uuid;
+use reqwest::header::HeaderMap;
+use crate::agent::{ContentBlock, ImageSource, Message, ModelEvent, ModelEvents, ModelProvider, ModelResponse, ToolDefinition, Usage};
+use crate::agent::retry::{RetryPolicy, send_with_retry};
+use crate::config::{AgentConfig, ClientConfig};
+
+const OPENAI_DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+const OPENAI_MAX_WIDTH: u32 = 1024;
+const OPENAI_MAX_HEIGHT: u32 = 768;
+
+const SYSTEM_PROMPT: &str = "You are a GUI agent operating a computer through the `computer` tool. \
+After each action you receive a new screenshot. When the task is done, or you need input from the user, \
+reply without calling any tool.";
+
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<ChatUsage>
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatUsage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+    #[serde(default)]
+    prompt_tokens_details: Option<PromptTokensDetails>
+}
+
+#[derive(Debug, Deserialize)]
+struct PromptTokensDetails {
+    #[serde(default)]
+    cached_tokens: u64
+}
+
+impl From<ChatUsage> for Usage {
+    fn from(usage: ChatUsage) -> Self {
+        // OpenAI counts cached tokens as part of the prompt tokens
+        let cached = usage.prompt_tokens_details.map(|d| d.cached_tokens).unwrap_or(0);
+        Usage {
+            input_tokens: usage.prompt_tokens.saturating_sub(cached),
+            output_tokens: usage.completion_tokens,
+            cache_creation_input_tokens: 0,
+            cache_read_input_tokens: cached,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Choice {
+    message: ChatMessage,
+    #[serde(default)]
+    finish_reason: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolCall {
+    #[serde(default)]
+    id: String,
+    function: FunctionCall
+}
+
+#[derive(Debug, Deserialize)]
+struct FunctionCall {
+    name: String,
+    arguments: String
+}
+
+/// Provider for OpenAI Chat Completions compatible endpoints, e.g. OpenAI itself or a
+/// self-hosted vLLM/Ollama server. The computer tool is exposed as a regular function.
+pub struct OpenAIProvider {
+    client: reqwest::Client,
+    api_key: Option<String>,
+    model: String,
+    base_url: String,
+    headers: HeaderMap,
+    retry: RetryPolicy,
+    max_tokens: Option<u32>,
+}
+
+impl OpenAIProvider {
+    pub fn create(model: String, client_config: &ClientConfig, agent_config: &AgentConfig) -> Result<Self> {
+        if agent_config.thinking_budget.is_some() {
+            eprintln!("Warning: Extended thinking is not supported by the OpenAI provider, the thinking budget is ignored");
+        }
+        let client = client_config.build_client()?;
+        // Self-hosted servers typically don't require a key
+        let api_key = std::env::var("OPENAI_API_KEY").ok();
+        let base_url = client_config.base_url.as_deref().unwrap_or(OPENAI_DEFAULT_BASE_URL);
+        Ok(OpenAIProvider {
+            client,
+            api_key,
+            model,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers: client_config.header_map()?,
+            retry: client_config.retry_policy(),
+            max_tokens: agent_config.max_tokens
+        })
+    }
+}
+
+fn computer_tool(display_width_px: u32, display_height_px: u32) -> Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": "computer",
+            "description": format!(
+                "Use a mouse and keyboard to interact with a computer and take screenshots. \
+                The screen is {display_width_px}x{display_height_px} pixels and coordinates are [x, y] \
+                in pixels from the top left corner. Clicks without a coordinate happen at the current \
+                cursor position. Key combinations are written like \"ctrl+c\"."
+            ),
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "action": {
+                        "type": "string",
+                        "enum": [
+                            "screenshot", "mouse_move", "cursor_position", "left_click", "right_click",
+                            "middle_click", "double_click", "triple_click", "type", "key", "scroll",
+                            "hold_key", "left_mouse_down", "left_mouse_up", "left_click_drag", "wait", "zoom"
+                        ]
+                    },
+                    "coordinate": {
+                        "type": "array",
+                        "items": { "type": "integer" },
+                        "minItems": 2,
+                        "maxItems": 2
+                    },
+                    "start_coordinate": {
+                        "type": "array",
+                        "items": { "type": "integer" },
+                        "minItems": 2,
+                        "maxItems": 2,
+                        "description": "Where left_click_drag starts, the current cursor position if not given"
+                    },
+                    "text": {
+                        "type": "string",
+                        "description": "Text to type, key combination to press, or modifier keys (e.g. \"shift\") to hold while clicking or scrolling"
+                    },
+                    "scroll_direction": { "type": "string", "enum": ["up", "down", "left", "right"] },
+                    "scroll_amount": { "type": "integer" },
+                    "duration": { "type": "number", "description": "Duration in seconds" },
+                    "region": {
+                        "type": "array",
+                        "items": { "type": "integer" },
+                        "minItems": 4,
+                        "maxItems": 4,
+                        "description": "[x1, y1, x2, y2] region to zoom into"
+                    }
+                },
+                "required": ["action"]
+            }
+        }
+    })
+}
+
+fn image_url(source: &ImageSource) -> Value {
+    let ImageSource::Base64 { media_type, data } = source;
+    json!({
+        "type": "image_url",
+        "image_url": { "url": format!("data:{media_type};base64,{data}") }
+    })
+}
+
+/// Convert the conversation to Chat Completions messages, with the user's system prompt
+/// appended to the built-in one. Tool messages can only hold
+/// text, so screenshots returned by tools are attached in a following user message.
+fn to_chat_messages(system: Option<&str>, messages: &[Message]) -> Result<Vec<Value>> {
+    let system = match system {
+        Some(system) => format!("{SYSTEM_PROMPT}\n\n{system}"),
+        None => SYSTEM_PROMPT.to_string()
+    };
+    let mut chat_messages = vec![json!({ "role": "system", "content": system })];
+    for message in messages {
+        if message.role == "assistant" {
+            let mut text = String::new();
+            let mut tool_calls = vec![];
+            for block in &message.content {
+                match block {
+                    ContentBlock::Text { text: t } => text.push_str(t),
+                    ContentBlock::ToolUse { id, name, input } => tool_calls.push(json!({
+                        "id": id,
+                        "type": "function",
+                        "function": { "name": name, "arguments": serde_json::to_string(input)? }
+                    })),
+                    _ => {}
+                }
+            }
+            let mut chat_message = json!({ "role": "assistant", "content": text });
+            if !tool_calls.is_empty() {
+                chat_message["tool_calls"] = json!(tool_calls);
+            }
+            chat_messages.push(chat_message);
+            continue;
+        }
+
+        let mut parts = vec![];
+        for block in &message.content {
+            match block {
+                ContentBlock::Text { text } => parts.push(json!({ "type": "text", "text": text })),
+                ContentBlock::Image { source } => parts.push(image_url(source)),
+                ContentBlock::ToolResult { content, tool_use_id, is_error } => {
+                    let mut result = vec![];
+                    for block in content {
+                        match block {
+                            ContentBlock::Text { text } => result.push(text.clone()),
+                            ContentBlock::Image { source } => {
+                                result.push("Screenshot attached below.".to_string());
+                                parts.push(image_url(source));
+                            }
+                            _ => {}
+                        }
+                    }
+                    if *is_error {
+                        result.insert(0, "Error:".to_string());
+                    }
+                    chat_messages.push(json!({
+                        "role": "tool",
+                        "tool_call_id": tool_use_id,
+                        "content": result.join("\n")
+                    }));
+                }
+                ContentBlock::ToolUse { .. } | ContentBlock::Thinking { .. } | ContentBlock::RedactedThinking { .. } => {}
+            }
+        }
+        if !parts.is_empty() {
+            chat_messages.push(json!({ "role": "user", "content": parts }));
+        }
+    }
+    Ok(chat_messages)
+}
+
+#[async_trait(?Send)]
+impl ModelProvider for OpenAIProvider {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn max_screenshot_size(&self) -> (u32, u32) {
+        (OPENAI_MAX_WIDTH, OPENAI_MAX_HEIGHT)
+    }
+
+    async fn get_response(&self, system: Option<&str>, tools: &[ToolDefinition], display_width_px: u32, display_height_px: u32, messages: &[Message], events: ModelEvents) -> Result<ModelResponse> {
+        let mut content = json!({
+            "model": self.model,
+            "tools": std::iter::once(computer_tool(display_width_px, display_height_px))
+                .chain(tools.iter().map(|tool| json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name,
+                        "description": tool.description,
+                        "parameters": tool.input_schema
+                    }
+                })))
+                .collect::<Vec<_>>(),
+            "messages": to_chat_messages(system, messages)?
+        });
+        if let Some(max_tokens) = self.max_tokens {
+            content["max_completion_tokens"] = json!(max_tokens);
+        }
+
+        let mut request = self.client.post(format!("{}/chat/completions", self.base_url))
+            .header("content-type", "application/json")
+            .json(&content);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        request = request.headers(self.headers.clone());
+        let resp = send_with_retry(&request, &self.retry).await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("OpenAI API request failed with status: {}\n{}", resp.status(), resp.text().await?));
+        }
+        let text = resp.text().await?;
+        let response = parse_response(&text)?;
+        for block in &response.content {
+            let _ = events.send(ModelEvent::Block(block.clone()));
+        }
+        Ok(response)
+    }
+}
+
+/// Parse a Chat Completions response body into content blocks
+fn parse_response(text: &str) -> Result<ModelResponse> {
+    let res: ChatResponse = match serde_json::from_str(text) {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("Failed to parse response: {e}\nResponse body:\n{text}");
+            return Err(e.into());
+        }
+    };
+    let usage = res.usage.map(Usage::from).unwrap_or_default();
+    let choice = res.choices.into_iter().next()
+        .ok_or_else(|| anyhow!("OpenAI API response contained no choices"))?;
+    if choice.finish_reason.as_deref() == Some("length") {
+        eprintln!("Warning: The response was cut off at the max tokens limit");
+    }
+    let message = choice.message;
+
+    let mut blocks = vec![];
+    if let Some(text) = message.content.filter(|t| !t.trim().is_empty()) {
+        blocks.push(ContentBlock::Text { text });
+    }
+    for call in message.tool_calls {
+        // Arguments that aren't valid JSON are kept as a string, which the agent reports to
+        // the model as an error instead of failing the whole response
+        let input = serde_json::from_str(&call.function.arguments)
+            .unwrap_or(Value::String(call.function.arguments));
+        let id = if call.id.is_empty() { Uuid::new_v4().to_string() } else { call.id };
+        blocks.push(ContentBlock::ToolUse { id, name: call.function.name, input });
+    }
+    Ok(ModelResponse { content: blocks, usage })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(data: &str) -> ContentBlock {
+        ContentBlock::Image { source: ImageSource::Base64 { media_type: "image/png".to_string(), data: data.to_string() } }
+    }
+
+    fn text(text: &str) -> ContentBlock {
+        ContentBlock::Text { text: text.to_string() }
+    }
+
+    #[test]
+    fn appends_system_prompt_to_the_built_in_one() {
+        let chat_messages = to_chat_messages(Some("Never submit forms"), &[]).unwrap();
+        assert_eq!(chat_messages, [json!({ "role": "system", "content": format!("{SYSTEM_PROMPT}\n\nNever submit forms") })]);
+    }
+
+    #[test]
+    fn sends_tool_results_before_their_screenshots() {
+        let messages = [
+            Message { role: "user".to_string(), content: vec![text("Close the dialog"), image("first")] },
+            Message { role: "assistant".to_string(), content: vec![
+                ContentBlock::Thinking { thinking: "The button is at the top".to_string(), signature: "sig".to_string() },
+                text("Clicking the button."),
+                ContentBlock::ToolUse { id: "call_1".to_string(), name: "computer".to_string(), input: json!({ "action": "left_click", "coordinate": [10, 20] }) },
+                ContentBlock::ToolUse { id: "call_2".to_string(), name: "computer".to_string(), input: json!({ "action": "key", "text": "notakey" }) },
+            ]},
+            Message { role: "user".to_string(), content: vec![
+                ContentBlock::ToolResult { content: vec![image("second")], tool_use_id: "call_1".to_string(), is_error: false },
+                ContentBlock::ToolResult { content: vec![text("Unknown key notakey")], tool_use_id: "call_2".to_string(), is_error: true },
+            ]},
+        ];
+        let chat_messages = to_chat_messages(None, &messages).unwrap();
+        assert_eq!(chat_messages[1..], [
+            json!({ "role": "user", "content": [
+                { "type": "text", "text": "Close the dialog" },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,first" } }
+            ]}),
+            json!({ "role": "assistant", "content": "Clicking the button.", "tool_calls": [
+                { "id": "call_1", "type": "function", "function": { "name": "computer", "arguments": r#"{"action":"left_click","coordinate":[10,20]}"# } },
+                { "id": "call_2", "type": "function", "function": { "name": "computer", "arguments": r#"{"action":"key","text":"notakey"}"# } }
+            ]}),
+            // Tool messages must directly follow the assistant message with the tool calls
+            json!({ "role": "tool", "tool_call_id": "call_1", "content": "Screenshot attached below." }),
+            json!({ "role": "tool", "tool_call_id": "call_2", "content": "Error:\nUnknown key notakey" }),
+            json!({ "role": "user", "content": [
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,second" } }
+            ]}),
+        ]);
+    }
+
+    fn response(message: Value, finish_reason: &str) -> String {
+        json!({
+            "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+            "usage": { "prompt_tokens": 1200, "completion_tokens": 30, "prompt_tokens_details": { "cached_tokens": 1000 } }
+        }).to_string()
+    }
+
+    #[test]
+    fn parses_text_and_tool_calls() {
+        let response = parse_response(&response(json!({
+            "role": "assistant",
+            "content": "Clicking the button.",
+            "tool_calls": [
+                { "id": "call_1", "type": "function", "function": { "name": "computer", "arguments": r#"{"action":"left_click","coordinate":[10,20]}"# } },
+                // Some servers leave out the id
+                { "type": "function", "function": { "name": "computer", "arguments": r#"{"action":"screenshot"}"# } }
+            ]
+        }), "tool_calls")).unwrap();
+        assert_eq!(serde_json::to_value(&response.content[..2]).unwrap(), json!([
+            { "type": "text", "text": "Clicking the button." },
+            { "type": "tool_use", "id": "call_1", "name": "computer", "input": { "action": "left_click", "coordinate": [10, 20] } }
+        ]));
+        let ContentBlock::ToolUse { id, input, .. } = &response.content[2] else { panic!("Expected a tool use") };
+        assert!(!id.is_empty());
+        assert_eq!(*input, json!({ "action": "screenshot" }));
+
+        // Cached tokens are part of the prompt tokens
+        assert_eq!(response.usage.input_tokens, 200);
+        assert_eq!(response.usage.cache_read_input_tokens, 1000);
+        assert_eq!(response.usage.output_tokens, 30);
+    }
+
+    #[test]
+    fn keeps_invalid_arguments_as_a_string() {
+        // A response cut off at the token limit, in the middle of the arguments
+        let response = parse_response(&response(json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{ "id": "call_1", "type": "function", "function": { "name": "computer", "arguments": r#"{"action":"type","text":"hel"# } }]
+        }), "length")).unwrap();
+        assert_eq!(serde_json::to_value(&response.content).unwrap(), json!([
+            { "type": "tool_use", "id": "call_1", "name": "computer", "input": r#"{"action":"type","text":"hel"# }
+        ]));
+    }
+
+    #[test]
+    fn parses_text_only_responses_without_usage() {
+        let body = json!({ "choices": [{ "message": { "role": "assistant", "content": "Done." } }] }).to_string();
+        let response = parse_response(&body).unwrap();
+        assert_eq!(serde_json::to_value(&response.content).unwrap(), json!([{ "type": "text", "text": "Done." }]));
+        assert_eq!(response.usage.input_tokens, 0);
+    }
+
+    #[test]
+    fn rejects_responses_without_choices() {
+        assert!(parse_response(r#"{ "choices": [] }"#).is_err());
+        assert!(parse_response("Bad gateway").is_err());
+    }
+
+    #[test]
+    fn omits_tool_calls_from_text_only_replies() {
+        let messages = [Message { role: "assistant".to_string(), content: vec![text("Done.")] }];
+        let chat_messages = to_chat_messages(None, &messages).unwrap();
+        assert_eq!(chat_messages[1], json!({ "role": "assistant", "content": "Done." }));
+    }
+}
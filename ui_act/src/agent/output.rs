@@ -0,0 +1,64 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::path::Path;
+use crate::agent::ToolDefinition;
+
+/// Name of the tool the model calls to finish with a structured result
+pub const RESULT_TOOL_NAME: &str = "submit_result";
+
+/// Sent when the model stops without submitting a result
+pub const RESULT_REMINDER: &str = "The task is not finished until you call the submit_result tool with the result.";
+
+/// How many times the model is reminded before the session is ended without a result
+pub const MAX_RESULT_REMINDERS: u32 = 3;
+
+/// A user supplied JSON schema that the final result of the task must match
+pub struct OutputSchema {
+    schema: Value,
+    validator: jsonschema::Validator,
+}
+
+impl OutputSchema {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read output schema {}", path.display()))?;
+        let schema: Value = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse output schema {}", path.display()))?;
+        Self::from_value(schema)
+    }
+
+    pub fn from_value(schema: Value) -> Result<Self> {
+        // Tool inputs are always objects
+        if schema["type"] != "object" {
+            return Err(anyhow!("Output schema must describe an object (\"type\": \"object\")"));
+        }
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| anyhow!("Invalid output schema: {}", e))?;
+        Ok(OutputSchema { schema, validator })
+    }
+
+    pub fn tool(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: RESULT_TOOL_NAME.to_string(),
+            description: "Submit the final result of the task. Call this once the task is complete, \
+                with the requested information as input. The session ends when the result is accepted."
+                .to_string(),
+            input_schema: self.schema.clone(),
+        }
+    }
+
+    /// Check a result against the schema, describing every violation on failure
+    pub fn validate(&self, result: &Value) -> Result<(), String> {
+        let errors: Vec<String> = self.validator.iter_errors(result)
+            .map(|e| match e.instance_path.to_string() {
+                path if path.is_empty() => e.to_string(),
+                path => format!("{}: {}", path, e)
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::agent::Usage;
+
+/// Prices in USD per million tokens
+struct Pricing {
+    input: f64,
+    output: f64,
+    cache_write: f64,
+    cache_read: f64,
+}
+
+const fn anthropic(input: f64, output: f64) -> Pricing {
+    // 5 minute cache writes cost 25% more than input, cache reads 10% of input
+    Pricing { input, output, cache_write: input * 1.25, cache_read: input * 0.1 }
+}
+
+const fn openai(input: f64, output: f64, cache_read: f64) -> Pricing {
+    Pricing { input, output, cache_write: input, cache_read }
+}
+
+/// List prices by model id prefix, the longest matching prefix is used
+const PRICES: &[(&str, Pricing)] = &[
+    ("claude-opus-4-6", anthropic(5.0, 25.0)),
+    ("claude-opus-4-5", anthropic(5.0, 25.0)),
+    ("claude-opus-4-1", anthropic(15.0, 75.0)),
+    ("claude-opus-4", anthropic(15.0, 75.0)),
+    ("claude-sonnet-4", anthropic(3.0, 15.0)),
+    ("claude-3-7-sonnet", anthropic(3.0, 15.0)),
+    ("claude-haiku-4-5", anthropic(1.0, 5.0)),
+    ("claude-3-5-haiku", anthropic(0.8, 4.0)),
+    ("gpt-4.1-nano", openai(0.1, 0.4, 0.025)),
+    ("gpt-4.1-mini", openai(0.4, 1.6, 0.1)),
+    ("gpt-4.1", openai(2.0, 8.0, 0.5)),
+    ("gpt-4o-mini", openai(0.15, 0.6, 0.075)),
+    ("gpt-4o", openai(2.5, 10.0, 1.25)),
+];
+
+/// Estimated cost in USD of the given usage, or None if the model has no known price
+pub fn estimate_cost(model: &str, usage: &Usage) -> Option<f64> {
+    let (_, pricing) = PRICES.iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())?;
+    let cost = usage.input_tokens as f64 * pricing.input
+        + usage.output_tokens as f64 * pricing.output
+        + usage.cache_creation_input_tokens as f64 * pricing.cache_write
+        + usage.cache_read_input_tokens as f64 * pricing.cache_read;
+    Some(cost / 1_000_000.0)
+}
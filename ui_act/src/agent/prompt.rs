@@ -0,0 +1,33 @@
+use anyhow::Result;
+use crate::env::ComputerEnvironment;
+
+/// Fill in `{{variable}}` placeholders in a system prompt template. Supported variables are
+/// `date`, `environment`, `window_title`, `screen_width` and `screen_height`. Unknown
+/// placeholders are left as is.
+pub fn render_system_prompt(template: &str, env: &dyn ComputerEnvironment) -> Result<String> {
+    let variables = [
+        ("date", chrono::Local::now().format("%Y-%m-%d").to_string()),
+        ("environment", env.name()),
+        ("window_title", env.window_title().unwrap_or_default()),
+        ("screen_width", env.width()?.to_string()),
+        ("screen_height", env.height()?.to_string()),
+    ];
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start..start + len + 4];
+        let name = placeholder[2..placeholder.len() - 2].trim();
+        match variables.iter().find(|(var, _)| *var == name) {
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(placeholder),
+        }
+        rest = &rest[start + len + 4..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Result};
+use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const INITIAL_DELAY: Duration = Duration::from_secs(2);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How model API requests are retried on rate limits, overload and network errors
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { max_retries: DEFAULT_MAX_RETRIES, initial_delay: INITIAL_DELAY, max_delay: MAX_DELAY }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff delay before retry number `attempt` (starting at 0)
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay)
+    }
+
+    /// Wait before retry number `attempt` (starting at 0) of a request that failed with `reason`.
+    /// Returns false without waiting once the retries are used up.
+    pub async fn wait_to_retry(&self, attempt: u32, reason: &str, requested_delay: Option<Duration>) -> bool {
+        if attempt >= self.max_retries {
+            return false;
+        }
+        let delay = requested_delay.unwrap_or_else(|| self.backoff(attempt));
+        eprintln!(
+            "Model API request failed ({}), retrying in {}s ({}/{})...",
+            reason, delay.as_secs_f32().ceil(), attempt + 1, self.max_retries
+        );
+        tokio::time::sleep(delay).await;
+        true
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    // 529 is used by Anthropic when the API is overloaded
+    status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::CONFLICT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
+/// Delay requested by the server through `retry-after-ms` or `retry-after` (in seconds)
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<f64>().ok();
+    if let Some(ms) = header("retry-after-ms") {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+    header("retry-after").map(|secs| Duration::from_secs_f64(secs.max(0.0)))
+}
+
+/// Send a request, retrying retryable failures according to `policy`. Once the retries are
+/// exhausted, the last response is returned as is so the caller can report its status.
+pub async fn send_with_retry(request: &RequestBuilder, policy: &RetryPolicy) -> Result<Response> {
+    let mut attempt = 0;
+    loop {
+        let result = request.try_clone()
+            .ok_or_else(|| anyhow!("Request body can not be retried"))?
+            .send()
+            .await;
+        let (reason, requested_delay) = match &result {
+            Ok(resp) if is_retryable_status(resp.status()) => (resp.status().to_string(), retry_after(resp.headers())),
+            Err(e) if !e.is_builder() => (e.to_string(), None),
+            _ => return Ok(result?),
+        };
+        if !policy.wait_to_retry(attempt, &reason, requested_delay).await {
+            return Ok(result?);
+        }
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn policy(max_retries: u32, initial_delay: Duration) -> RetryPolicy {
+        RetryPolicy { max_retries, initial_delay, max_delay: MAX_DELAY }
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_max_delay() {
+        let policy = RetryPolicy::default();
+        for (attempt, secs) in [(0, 2), (1, 4), (2, 8), (3, 16), (4, 32), (5, 60), (6, 60), (40, 60)] {
+            assert_eq!(policy.backoff(attempt), Duration::from_secs(secs), "attempt {}", attempt);
+        }
+    }
+
+    #[test]
+    fn reads_the_requested_delay() {
+        for (headers, delay) in [
+            (vec![("retry-after-ms", "1500")], Some(Duration::from_millis(1500))),
+            (vec![("retry-after", "3")], Some(Duration::from_secs(3))),
+            (vec![("retry-after", " 0.5 ")], Some(Duration::from_millis(500))),
+            (vec![("retry-after", "3"), ("retry-after-ms", "250")], Some(Duration::from_millis(250))),
+            (vec![("retry-after", "-1")], Some(Duration::ZERO)),
+            // HTTP dates are not supported, the backoff is used instead
+            (vec![("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")], None),
+            (vec![], None),
+        ] {
+            let mut map = HeaderMap::new();
+            for (name, value) in &headers {
+                map.insert(*name, HeaderValue::from_static(value));
+            }
+            assert_eq!(retry_after(&map), delay, "{:?}", headers);
+        }
+    }
+
+    #[test]
+    fn retries_rate_limits_timeouts_and_server_errors() {
+        for (status, retryable) in [
+            (408, true), (409, true), (429, true), (500, true), (502, true), (503, true), (529, true),
+            (200, false), (400, false), (401, false), (403, false), (404, false), (413, false),
+        ] {
+            assert_eq!(is_retryable_status(StatusCode::from_u16(status).unwrap()), retryable, "status {}", status);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_only_while_retries_are_left() {
+        let policy = policy(2, Duration::from_secs(2));
+        let started = tokio::time::Instant::now();
+        assert!(policy.wait_to_retry(1, "503", None).await);
+        assert_eq!(started.elapsed(), Duration::from_secs(4));
+        assert!(policy.wait_to_retry(0, "429", Some(Duration::from_secs(7))).await);
+        assert_eq!(started.elapsed(), Duration::from_secs(11));
+        assert!(!policy.wait_to_retry(2, "503", None).await);
+        assert_eq!(started.elapsed(), Duration::from_secs(11));
+    }
+
+    /// Serve the given status codes in order, one per request, and return the server's url
+    async fn serve(statuses: Vec<u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let _ = stream.read(&mut request).await.unwrap();
+                let response = format!("HTTP/1.1 {} Status\r\ncontent-length: 0\r\nretry-after-ms: 1\r\nconnection: close\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_request_succeeds() {
+        let url = serve(vec![503, 429, 200]).await;
+        let request = reqwest::Client::builder().no_proxy().build().unwrap().get(url);
+        let resp = send_with_retry(&request, &policy(5, Duration::from_millis(1))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_response_once_retries_are_exhausted() {
+        let url = serve(vec![503, 503, 503, 200]).await;
+        let request = reqwest::Client::builder().no_proxy().build().unwrap().get(url);
+        let resp = send_with_retry(&request, &policy(2, Duration::from_millis(1))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
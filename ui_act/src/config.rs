@@ -0,0 +1,301 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::agent::retry::RetryPolicy;
+
+const DEFAULT_MAX_SCREENSHOTS: usize = 10;
+
+/// Settings read from `$XDG_CONFIG_HOME/ui-act/config.toml`. Environment variables
+/// override the file, and command line flags override both.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub agent: AgentConfig,
+    pub anthropic: ClientConfig,
+    pub openai: ClientConfig,
+}
+
+/// Settings for the agent loop, independent of the model provider
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    /// Number of most recent screenshots sent as images, older ones are replaced by a placeholder
+    pub max_screenshots: Option<usize>,
+    /// Budgets after which the session is stopped
+    pub max_actions: Option<u32>,
+    pub max_api_calls: Option<u32>,
+    pub max_total_tokens: Option<u64>,
+    /// Estimated cost in USD
+    pub max_cost: Option<f64>,
+    /// Wall-clock time in seconds
+    pub timeout: Option<u64>,
+    /// Standing instructions for the model, see `render_system_prompt` for the template variables
+    pub system_prompt: Option<String>,
+    /// Maximum number of tokens the model may generate per response, including thinking
+    pub max_tokens: Option<u32>,
+    /// Enables extended thinking with this many tokens to think per response
+    pub thinking_budget: Option<u32>,
+    /// End the session when the model stops calling tools, instead of asking the user for input
+    pub non_interactive: bool,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            max_screenshots: Some(DEFAULT_MAX_SCREENSHOTS),
+            max_actions: None,
+            max_api_calls: None,
+            max_total_tokens: None,
+            max_cost: None,
+            timeout: None,
+            system_prompt: None,
+            max_tokens: None,
+            thinking_budget: None,
+            non_interactive: false,
+        }
+    }
+}
+
+impl AgentConfig {
+    /// Check the values that the command line flags check when parsing
+    fn validate(&self) -> Result<()> {
+        if self.max_screenshots == Some(0) {
+            return Err(anyhow!("max_screenshots must be a positive int"));
+        }
+        Ok(())
+    }
+
+    /// Override with the settings that are set in `other`
+    pub fn merge(&mut self, other: AgentConfig) {
+        self.max_screenshots = other.max_screenshots.or(self.max_screenshots);
+        self.max_actions = other.max_actions.or(self.max_actions);
+        self.max_api_calls = other.max_api_calls.or(self.max_api_calls);
+        self.max_total_tokens = other.max_total_tokens.or(self.max_total_tokens);
+        self.max_cost = other.max_cost.or(self.max_cost);
+        self.timeout = other.timeout.or(self.timeout);
+        if other.system_prompt.is_some() {
+            self.system_prompt = other.system_prompt;
+        }
+        self.max_tokens = other.max_tokens.or(self.max_tokens);
+        self.thinking_budget = other.thinking_budget.or(self.thinking_budget);
+        self.non_interactive |= other.non_interactive;
+    }
+}
+
+/// HTTP client settings for a model provider
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub base_url: Option<String>,
+    /// Proxy for all requests. If unset, the `HTTPS_PROXY`/`HTTP_PROXY` variables are used
+    pub proxy: Option<String>,
+    /// PEM file with additional root certificates to trust
+    pub ca_bundle: Option<PathBuf>,
+    pub headers: BTreeMap<String, String>,
+    /// Number of times a failed request is retried before the session fails
+    pub max_retries: Option<u32>,
+}
+
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("ui-act").join("config.toml"))
+}
+
+impl Config {
+    /// Load the config file (if present) and apply environment overrides
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None => match default_config_path() {
+                Some(path) if path.exists() => Config::from_file(&path)?,
+                _ => Config::default()
+            }
+        };
+        config.anthropic.apply_env("ANTHROPIC")?;
+        config.openai.apply_env("OPENAI")?;
+        config.agent.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+}
+
+impl ClientConfig {
+    /// Override settings from `<PREFIX>_BASE_URL`, `<PREFIX>_PROXY`, `<PREFIX>_CA_BUNDLE` and
+    /// `<PREFIX>_CUSTOM_HEADERS`, where the latter holds newline separated "Name: value" pairs
+    fn apply_env(&mut self, prefix: &str) -> Result<()> {
+        if let Ok(base_url) = std::env::var(format!("{prefix}_BASE_URL")) {
+            self.base_url = Some(base_url);
+        }
+        if let Ok(proxy) = std::env::var(format!("{prefix}_PROXY")) {
+            self.proxy = Some(proxy);
+        }
+        if let Some(ca_bundle) = std::env::var_os(format!("{prefix}_CA_BUNDLE")) {
+            self.ca_bundle = Some(PathBuf::from(ca_bundle));
+        }
+        if let Ok(headers) = std::env::var(format!("{prefix}_CUSTOM_HEADERS")) {
+            for line in headers.lines().filter(|l| !l.trim().is_empty()) {
+                let (name, value) = parse_header(line)?;
+                self.headers.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Override with the settings that are set in `other`
+    pub fn merge(&mut self, other: ClientConfig) {
+        if other.base_url.is_some() {
+            self.base_url = other.base_url;
+        }
+        if other.proxy.is_some() {
+            self.proxy = other.proxy;
+        }
+        if other.ca_bundle.is_some() {
+            self.ca_bundle = other.ca_bundle;
+        }
+        self.headers.extend(other.headers);
+        if other.max_retries.is_some() {
+            self.max_retries = other.max_retries;
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let mut policy = RetryPolicy::default();
+        if let Some(max_retries) = self.max_retries {
+            policy.max_retries = max_retries;
+        }
+        policy
+    }
+
+    pub fn build_client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)
+                .with_context(|| format!("Invalid proxy url: {proxy}"))?);
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            let pem = std::fs::read(ca_bundle)
+                .with_context(|| format!("Failed to read CA bundle {}", ca_bundle.display()))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Extra headers to send with every request. These replace any default header with the same name.
+    pub fn header_map(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).with_context(|| format!("Invalid header name: {name}"))?,
+                HeaderValue::from_str(value).with_context(|| format!("Invalid value for header {name}"))?
+            );
+        }
+        Ok(headers)
+    }
+}
+
+/// Parse a "Name: value" header line
+pub fn parse_header(line: &str) -> Result<(String, String)> {
+    let (name, value) = line.split_once(':')
+        .filter(|(name, _)| !name.trim().is_empty())
+        .ok_or_else(|| anyhow!("Invalid header \"{}\", expected \"Name: value\"", line))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(text: &str) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), text).unwrap();
+        file
+    }
+
+    #[test]
+    fn overrides_defaults_with_file_and_flags() {
+        let file = write_config("[agent]\nmax_screenshots = 4\nmax_actions = 5\nnon_interactive = true\n");
+        let mut agent = Config::load(Some(file.path())).unwrap().agent;
+        assert_eq!((agent.max_screenshots, agent.max_actions, agent.timeout), (Some(4), Some(5), None));
+
+        // Flags that aren't given leave the file and default values
+        agent.merge(AgentConfig { max_screenshots: Some(2), timeout: Some(60), ..AgentConfig::default() });
+        assert_eq!((agent.max_screenshots, agent.max_actions, agent.timeout), (Some(2), Some(5), Some(60)));
+        assert!(agent.non_interactive);
+        assert_eq!(AgentConfig::default().max_screenshots, Some(DEFAULT_MAX_SCREENSHOTS));
+    }
+
+    #[test]
+    fn overrides_client_file_settings_with_env_and_flags() {
+        let file = write_config(r#"
+            [anthropic]
+            base_url = "https://file.example.com"
+            proxy = "http://proxy.file.example.com:3128"
+            max_retries = 3
+            headers = { X-A = "file", X-B = "file", X-C = "file" }
+        "#);
+        // Not loaded with `Config::load`, which would apply the real ANTHROPIC_ variables
+        let mut client = Config::from_file(file.path()).unwrap().anthropic;
+        // A prefix of its own, so that no other test sees these variables
+        unsafe {
+            std::env::set_var("UI_ACT_TEST_PRECEDENCE_BASE_URL", "https://env.example.com");
+            std::env::set_var("UI_ACT_TEST_PRECEDENCE_CUSTOM_HEADERS", "X-B: env\n\nX-C: env");
+        }
+        client.apply_env("UI_ACT_TEST_PRECEDENCE").unwrap();
+        assert_eq!(client.base_url.as_deref(), Some("https://env.example.com"));
+
+        let mut flags = ClientConfig { base_url: Some("https://flag.example.com".to_string()), ..ClientConfig::default() };
+        flags.headers.insert("X-C".to_string(), "flag".to_string());
+        client.merge(flags);
+        assert_eq!(client.base_url.as_deref(), Some("https://flag.example.com"));
+        assert_eq!(client.proxy.as_deref(), Some("http://proxy.file.example.com:3128"));
+        assert_eq!(client.ca_bundle, None);
+        assert_eq!(client.retry_policy().max_retries, 3);
+        let headers: Vec<_> = client.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(headers, [("X-A", "file"), ("X-B", "env"), ("X-C", "flag")]);
+        assert_eq!(ClientConfig::default().retry_policy().max_retries, RetryPolicy::default().max_retries);
+    }
+
+    #[test]
+    fn parses_headers() {
+        assert_eq!(parse_header("X-Api-Key: abc").unwrap(), ("X-Api-Key".to_string(), "abc".to_string()));
+        assert_eq!(parse_header(" X-Trace :  a:b ").unwrap(), ("X-Trace".to_string(), "a:b".to_string()));
+        assert_eq!(parse_header("X-Empty:").unwrap(), ("X-Empty".to_string(), String::new()));
+        for malformed in ["X-Api-Key abc", ": abc", ""] {
+            assert!(parse_header(malformed).is_err(), "{:?}", malformed);
+        }
+
+        // Names and values are checked when the headers are used
+        let mut client = ClientConfig::default();
+        client.headers.insert("X Api Key".to_string(), "abc".to_string());
+        assert!(client.header_map().is_err());
+        let mut client = ClientConfig::default();
+        client.headers.insert("X-Api-Key".to_string(), "a\nb".to_string());
+        assert!(client.header_map().is_err());
+
+        unsafe {
+            std::env::set_var("UI_ACT_TEST_MALFORMED_CUSTOM_HEADERS", "X-Api-Key abc");
+        }
+        assert!(ClientConfig::default().apply_env("UI_ACT_TEST_MALFORMED").is_err());
+    }
+
+    #[test]
+    fn rejects_zero_max_screenshots() {
+        let file = write_config("[agent]\nmax_screenshots = 0\n");
+        let error = Config::load(Some(file.path())).unwrap_err();
+        assert!(error.to_string().contains("max_screenshots"));
+        let file = write_config("[agent]\nmax_screenshots = 3\n");
+        assert_eq!(Config::load(Some(file.path())).unwrap().agent.max_screenshots, Some(3));
+    }
+}
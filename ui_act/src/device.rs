@@ -38,7 +38,7 @@ impl XInputMaster {
     }
 }
 
-fn get_device_id_by_name(name: &str) -> Result<i32> {
+pub fn get_device_id_by_name(name: &str) -> Result<i32> {
     let output = Command::new("xinput").arg("list").output()?;
     let stdout = String::from_utf8_lossy(&output.stdout);
     for line in stdout.lines() {
@@ -76,8 +76,8 @@ pub static SYNC_DELAY: Duration = Duration::from_micros(1); // should relinquish
 
 // Mouse Device Statics
 
-static CLICK_DELAY: Duration = Duration::from_millis(100);
-static MULTI_CLICK_DELAY: Duration = Duration::from_millis(50);
+pub static CLICK_DELAY: Duration = Duration::from_millis(100);
+pub static MULTI_CLICK_DELAY: Duration = Duration::from_millis(50);
 
 pub enum MouseButton {
     Left,
@@ -263,7 +263,7 @@ impl MouseDevice {
 }
 
 
-static KEY_PRESS_DELAY: Duration = Duration::from_millis(50);
+pub static KEY_PRESS_DELAY: Duration = Duration::from_millis(50);
 
 pub struct KeyboardDevice {
     pub id: i32,
@@ -333,6 +333,24 @@ impl KeyboardDevice {
         Ok(())
     }
 
+    /// Press and keep holding all keys of a combination, e.g. modifiers for a click
+    pub fn keys_down(&mut self, key_combination: &str) -> Result<()> {
+        let keys = parse_key_combination(key_combination)?;
+        for key in keys.keys.iter() {
+            self.key_down(*key)?;
+        }
+        Ok(())
+    }
+
+    /// Release all keys of a combination, in reverse order
+    pub fn keys_up(&mut self, key_combination: &str) -> Result<()> {
+        let keys = parse_key_combination(key_combination)?;
+        for key in keys.keys.iter().rev() {
+            self.key_up(*key)?;
+        }
+        Ok(())
+    }
+
     pub fn hold_key(&mut self, key_combination: &str, duration: Duration) -> Result<()> {
         let keys = parse_key_combination(key_combination)?;
         for key in keys.keys.iter() {
@@ -366,11 +384,16 @@ impl KeyboardDevice {
 }
 
 
-struct KeyCombination {
-    keys: Vec<uinput::event::keyboard::Key>,
+pub struct KeyCombination {
+    pub keys: Vec<uinput::event::keyboard::Key>,
+}
+
+/// Check that a key combination only names known keys, without pressing it
+pub fn check_key_combination(combination: &str) -> Result<()> {
+    parse_key_combination(combination).map(|_| ())
 }
 
-fn parse_key_combination(combination: &str) -> Result<KeyCombination> {
+pub fn parse_key_combination(combination: &str) -> Result<KeyCombination> {
     use uinput::event::keyboard::Key;
     
     let parts: Vec<&str> = combination.split('+').collect();
@@ -451,7 +474,7 @@ fn parse_key_combination(combination: &str) -> Result<KeyCombination> {
     Ok(KeyCombination { keys })
 }
 
-fn char_to_keys(c: char) -> Vec<uinput::event::keyboard::Key> {
+pub fn char_to_keys(c: char) -> Vec<uinput::event::keyboard::Key> {
     use uinput::event::keyboard::Key;
     let mut keys = vec![];
     if c.is_uppercase() {
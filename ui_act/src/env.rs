@@ -1,13 +1,22 @@
 use anyhow::Result;
 use std::time::Duration;
 
+pub mod dry_run;
+#[cfg(test)]
+pub mod fake;
 pub mod full_desktop;
+pub mod multi_window;
+pub mod nested_display;
 pub mod single_window;
 
 pub trait ComputerEnvironment {
     fn name(&self) -> String;
     fn width(&self) -> Result<u32>;
     fn height(&self) -> Result<u32>;
+    /// Title of the controlled window, if the environment is limited to one
+    fn window_title(&self) -> Option<String> {
+        None
+    }
     
     // General actions
     fn screenshot(&self) -> Result<image::RgbImage>;
@@ -30,6 +39,8 @@ pub trait ComputerEnvironment {
     
     // Keyboard actions
     fn hold_key(&mut self, key: &str, duration: Duration) -> Result<()>;
+    fn key_down(&mut self, key_combination: &str) -> Result<()>;
+    fn key_up(&mut self, key_combination: &str) -> Result<()>;
     fn type_text(&mut self, text: &str) -> Result<()>;
     fn press_key(&mut self, key_combination: &str) -> Result<()>;
 
@@ -0,0 +1,231 @@
+use anyhow::{anyhow, Context, Result};
+use image::{Rgb, RgbImage};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::env::ComputerEnvironment;
+use crate::device::{check_key_combination, ScrollDirection};
+
+const LOG_FILE: &str = "actions.log";
+const MARKER_COLOR: Rgb<u8> = Rgb([255, 0, 64]);
+const MARKER_RADIUS: i32 = 16;
+
+/// Wraps an environment so that screenshots are real but mouse and keyboard actions are never
+/// executed. Each intended action is printed and logged to `actions.log`, next to a screenshot
+/// with the action marked on it. The pointer position is tracked virtually.
+pub struct DryRunEnvironment {
+    inner: Box<dyn ComputerEnvironment>,
+    log_dir: PathBuf,
+    log: File,
+    index: u32,
+    cursor: (u32, u32),
+}
+
+impl DryRunEnvironment {
+    pub fn create(mut inner: Box<dyn ComputerEnvironment>, log_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(log_dir)
+            .with_context(|| format!("Failed to create dry run directory {}", log_dir.display()))?;
+        let log = File::create(log_dir.join(LOG_FILE))
+            .with_context(|| format!("Failed to create dry run log in {}", log_dir.display()))?;
+        // Reading the pointer position doesn't act on the environment
+        let cursor = inner.cursor_position().unwrap_or((0, 0));
+        Ok(DryRunEnvironment { inner, log_dir: log_dir.to_path_buf(), log, index: 0, cursor })
+    }
+
+    /// Refuse coordinates off the screen, like the wrapped environment would
+    fn check_bounds(&self, x: u32, y: u32) -> Result<()> {
+        let (width, height) = (self.inner.width()?, self.inner.height()?);
+        if x >= width || y >= height {
+            return Err(anyhow!("Mouse coordinates ({}, {}) exceed the screen dimensions ({}x{})", x, y, width, height));
+        }
+        Ok(())
+    }
+
+    /// Log an action, with a marker at the pointer and a line from `from` if it moved
+    fn log_action(&mut self, description: &str, from: Option<(u32, u32)>) -> Result<()> {
+        self.index += 1;
+        let mut screenshot = self.inner.screenshot()?;
+        if let Some(from) = from {
+            draw_line(&mut screenshot, from, self.cursor);
+        }
+        draw_marker(&mut screenshot, self.cursor);
+        let file = format!("{:04}.png", self.index);
+        screenshot.save(self.log_dir.join(&file))?;
+
+        let line = format!("{} {} at ({}, {})", file, description, self.cursor.0, self.cursor.1);
+        println!("[dry run] {}", line);
+        writeln!(self.log, "{}", line)?;
+        Ok(())
+    }
+}
+
+impl ComputerEnvironment for DryRunEnvironment {
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    fn width(&self) -> Result<u32> {
+        self.inner.width()
+    }
+
+    fn height(&self) -> Result<u32> {
+        self.inner.height()
+    }
+
+    fn window_title(&self) -> Option<String> {
+        self.inner.window_title()
+    }
+
+    fn screenshot(&self) -> Result<RgbImage> {
+        self.inner.screenshot()
+    }
+
+    fn wait(&mut self, duration: Duration) -> Result<()> {
+        self.inner.wait(duration)
+    }
+
+    fn scroll(&mut self, direction: &str, amount: u32) -> Result<()> {
+        ScrollDirection::from_str(direction)?;
+        self.log_action(&format!("scroll {} by {}", direction, amount), None)
+    }
+
+    // Mouse actions
+
+    fn mouse_move(&mut self, x: u32, y: u32) -> Result<()> {
+        self.check_bounds(x, y)?;
+        let from = self.cursor;
+        self.cursor = (x, y);
+        self.log_action("mouse_move", Some(from))
+    }
+
+    fn cursor_position(&mut self) -> Result<(u32, u32)> {
+        Ok(self.cursor)
+    }
+
+    fn left_mouse_down(&mut self) -> Result<()> {
+        self.log_action("left_mouse_down", None)
+    }
+
+    fn left_mouse_up(&mut self) -> Result<()> {
+        self.log_action("left_mouse_up", None)
+    }
+
+    fn left_click(&mut self) -> Result<()> {
+        self.log_action("left_click", None)
+    }
+
+    fn left_click_drag(&mut self, x: u32, y: u32) -> Result<()> {
+        self.check_bounds(x, y)?;
+        let from = self.cursor;
+        self.cursor = (x, y);
+        self.log_action(&format!("left_click_drag from ({}, {})", from.0, from.1), Some(from))
+    }
+
+    fn right_click(&mut self) -> Result<()> {
+        self.log_action("right_click", None)
+    }
+
+    fn middle_click(&mut self) -> Result<()> {
+        self.log_action("middle_click", None)
+    }
+
+    fn double_click(&mut self) -> Result<()> {
+        self.log_action("double_click", None)
+    }
+
+    fn triple_click(&mut self) -> Result<()> {
+        self.log_action("triple_click", None)
+    }
+
+    // Keyboard actions
+
+    fn hold_key(&mut self, key: &str, duration: Duration) -> Result<()> {
+        check_key_combination(key)?;
+        self.log_action(&format!("hold_key {:?} for {:?}", key, duration), None)
+    }
+
+    fn key_down(&mut self, key_combination: &str) -> Result<()> {
+        check_key_combination(key_combination)?;
+        self.log_action(&format!("key_down {:?}", key_combination), None)
+    }
+
+    fn key_up(&mut self, key_combination: &str) -> Result<()> {
+        check_key_combination(key_combination)?;
+        self.log_action(&format!("key_up {:?}", key_combination), None)
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.log_action(&format!("type {:?}", text), None)
+    }
+
+    fn press_key(&mut self, key_combination: &str) -> Result<()> {
+        check_key_combination(key_combination)?;
+        self.log_action(&format!("key {:?}", key_combination), None)
+    }
+}
+
+fn put_pixel(image: &mut RgbImage, x: i32, y: i32) {
+    if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+        image.put_pixel(x as u32, y as u32, MARKER_COLOR);
+    }
+}
+
+/// A ring with a crosshair, three pixels thick so it's visible on any background
+fn draw_marker(image: &mut RgbImage, (x, y): (u32, u32)) {
+    let (x, y) = (x as i32, y as i32);
+    for dy in -MARKER_RADIUS - 1..=MARKER_RADIUS + 1 {
+        for dx in -MARKER_RADIUS - 1..=MARKER_RADIUS + 1 {
+            let distance = ((dx * dx + dy * dy) as f32).sqrt();
+            let on_ring = (distance - MARKER_RADIUS as f32).abs() <= 1.5;
+            let on_cross = (dx.abs() <= 1 || dy.abs() <= 1) && distance <= MARKER_RADIUS as f32 / 2.0;
+            if on_ring || on_cross {
+                put_pixel(image, x + dx, y + dy);
+            }
+        }
+    }
+}
+
+fn draw_line(image: &mut RgbImage, (x0, y0): (u32, u32), (x1, y1): (u32, u32)) {
+    let (x0, y0, x1, y1) = (x0 as f32, y0 as f32, x1 as f32, y1 as f32);
+    let steps = (x1 - x0).abs().max((y1 - y0).abs()).max(1.0) as i32;
+    for i in 0..=steps {
+        let t = i as f32 / steps as f32;
+        let (x, y) = ((x0 + (x1 - x0) * t) as i32, (y0 + (y1 - y0) * t) as i32);
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1)] {
+            put_pixel(image, x + dx, y + dy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::fake::FakeEnvironment;
+
+    #[test]
+    fn logs_actions_without_executing_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let fake = FakeEnvironment::create(640, 480).fill((180, 80, 40, 40), Rgb([0, 0, 255]));
+        let calls = fake.calls.clone();
+        let mut env = DryRunEnvironment::create(Box::new(fake), dir.path()).unwrap();
+        env.mouse_move(200, 100).unwrap();
+        env.left_click().unwrap();
+        env.type_text("hello").unwrap();
+        assert!(env.press_key("notakey").is_err());
+        assert!(env.mouse_move(640, 100).is_err());
+        assert!(env.left_click_drag(200, 480).is_err());
+
+        assert!(calls.borrow().is_empty());
+        assert_eq!(env.cursor_position().unwrap(), (200, 100));
+        let log = std::fs::read_to_string(dir.path().join(LOG_FILE)).unwrap();
+        assert_eq!(log.lines().collect::<Vec<_>>(), [
+            "0001.png mouse_move at (200, 100)",
+            "0002.png left_click at (200, 100)",
+            "0003.png type \"hello\" at (200, 100)",
+        ]);
+        let screenshot = image::open(dir.path().join("0002.png")).unwrap().to_rgb8();
+        assert_eq!(*screenshot.get_pixel(200, 100), MARKER_COLOR);
+        assert_eq!(*screenshot.get_pixel(185, 85), Rgb([0, 0, 255]));
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::{anyhow, Result};
+use image::{Rgb, RgbImage};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+use crate::env::ComputerEnvironment;
+use crate::device::{check_key_combination, ScrollDirection};
+
+const BACKGROUND: Rgb<u8> = Rgb([240, 240, 240]);
+
+/// A call made to the environment, with the pointer position for clicks
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    Wait(Duration),
+    Scroll { direction: String, amount: u32 },
+    MouseMove { x: u32, y: u32 },
+    LeftMouseDown { x: u32, y: u32 },
+    LeftMouseUp { x: u32, y: u32 },
+    LeftClick { x: u32, y: u32 },
+    LeftClickDrag { x: u32, y: u32 },
+    RightClick { x: u32, y: u32 },
+    MiddleClick { x: u32, y: u32 },
+    DoubleClick { x: u32, y: u32 },
+    TripleClick { x: u32, y: u32 },
+    HoldKey { key: String, duration: Duration },
+    KeyDown(String),
+    KeyUp(String),
+    TypeText(String),
+    PressKey(String),
+}
+
+/// A rectangle (x, y, width, height) on the screen
+pub type Rect = (u32, u32, u32, u32);
+
+type ClickHandler = Box<dyn FnMut(&mut RgbImage)>;
+
+/// An environment without X server or input devices. The screen is an in-memory framebuffer,
+/// every action is recorded in `calls`, and clicks can be scripted to change the screen.
+/// Actions fail like the real environment for unknown keys and positions off the screen.
+pub struct FakeEnvironment {
+    framebuffer: RgbImage,
+    cursor: (u32, u32),
+    click_handlers: Vec<(Rect, ClickHandler)>,
+    /// Click handlers only change the screen on the next wait, like an animated UI
+    animated: bool,
+    /// Click handlers run on the next wait
+    pending_handlers: Vec<usize>,
+    /// Actions left before the environment fails like a lost X connection
+    remaining_actions: Option<usize>,
+    pub calls: Rc<RefCell<Vec<Call>>>,
+}
+
+impl FakeEnvironment {
+    pub fn create(width: u32, height: u32) -> Self {
+        FakeEnvironment {
+            framebuffer: RgbImage::from_pixel(width, height, BACKGROUND),
+            cursor: (0, 0),
+            click_handlers: vec![],
+            animated: false,
+            pending_handlers: vec![],
+            remaining_actions: None,
+            calls: Rc::new(RefCell::new(vec![])),
+        }
+    }
+
+    /// Draw a filled rectangle on the screen
+    pub fn fill(mut self, rect: Rect, color: Rgb<u8>) -> Self {
+        fill(&mut self.framebuffer, rect, color);
+        self
+    }
+
+    /// Change the screen when `rect` is left clicked
+    pub fn on_click(mut self, rect: Rect, handler: impl FnMut(&mut RgbImage) + 'static) -> Self {
+        self.click_handlers.push((rect, Box::new(handler)));
+        self
+    }
+
+    /// Delay the screen changes of clicks until the environment waits
+    pub fn animated(mut self) -> Self {
+        self.animated = true;
+        self
+    }
+
+    /// Fail every action after the first `actions` with a connection error
+    pub fn disconnect_after(mut self, actions: usize) -> Self {
+        self.remaining_actions = Some(actions);
+        self
+    }
+
+    fn record(&mut self, call: Call) -> Result<()> {
+        match &mut self.remaining_actions {
+            Some(0) => {
+                let error = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Connection to the X server lost");
+                return Err(x11rb::errors::ConnectionError::IoError(error).into());
+            }
+            Some(remaining) => *remaining -= 1,
+            None => {}
+        }
+        self.calls.borrow_mut().push(call);
+        Ok(())
+    }
+
+    fn record_click(&mut self, call: impl FnOnce(u32, u32) -> Call) -> Result<()> {
+        let (x, y) = self.cursor;
+        self.record(call(x, y))
+    }
+
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()> {
+        if x >= self.framebuffer.width() || y >= self.framebuffer.height() {
+            return Err(anyhow!("Coordinate ({}, {}) is outside the screen", x, y));
+        }
+        self.cursor = (x, y);
+        Ok(())
+    }
+}
+
+impl ComputerEnvironment for FakeEnvironment {
+    fn name(&self) -> String {
+        "fake".to_string()
+    }
+
+    fn width(&self) -> Result<u32> {
+        Ok(self.framebuffer.width())
+    }
+
+    fn height(&self) -> Result<u32> {
+        Ok(self.framebuffer.height())
+    }
+
+    fn screenshot(&self) -> Result<RgbImage> {
+        Ok(self.framebuffer.clone())
+    }
+
+    fn wait(&mut self, duration: Duration) -> Result<()> {
+        self.record(Call::Wait(duration))?;
+        for index in std::mem::take(&mut self.pending_handlers) {
+            (self.click_handlers[index].1)(&mut self.framebuffer);
+        }
+        Ok(())
+    }
+
+    fn scroll(&mut self, direction: &str, amount: u32) -> Result<()> {
+        ScrollDirection::from_str(direction)?;
+        self.record(Call::Scroll { direction: direction.to_string(), amount })
+    }
+
+    // Mouse actions
+
+    fn mouse_move(&mut self, x: u32, y: u32) -> Result<()> {
+        self.move_to(x, y)?;
+        self.record(Call::MouseMove { x, y })
+    }
+
+    fn cursor_position(&mut self) -> Result<(u32, u32)> {
+        Ok(self.cursor)
+    }
+
+    fn left_mouse_down(&mut self) -> Result<()> {
+        self.record_click(|x, y| Call::LeftMouseDown { x, y })
+    }
+
+    fn left_mouse_up(&mut self) -> Result<()> {
+        self.record_click(|x, y| Call::LeftMouseUp { x, y })
+    }
+
+    fn left_click(&mut self) -> Result<()> {
+        self.record_click(|x, y| Call::LeftClick { x, y })?;
+        let (x, y) = self.cursor;
+        for (index, ((rx, ry, rw, rh), handler)) in self.click_handlers.iter_mut().enumerate() {
+            if x >= *rx && x < *rx + *rw && y >= *ry && y < *ry + *rh {
+                if self.animated {
+                    self.pending_handlers.push(index);
+                } else {
+                    handler(&mut self.framebuffer);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn left_click_drag(&mut self, x: u32, y: u32) -> Result<()> {
+        self.move_to(x, y)?;
+        self.record(Call::LeftClickDrag { x, y })
+    }
+
+    fn right_click(&mut self) -> Result<()> {
+        self.record_click(|x, y| Call::RightClick { x, y })
+    }
+
+    fn middle_click(&mut self) -> Result<()> {
+        self.record_click(|x, y| Call::MiddleClick { x, y })
+    }
+
+    fn double_click(&mut self) -> Result<()> {
+        self.record_click(|x, y| Call::DoubleClick { x, y })
+    }
+
+    fn triple_click(&mut self) -> Result<()> {
+        self.record_click(|x, y| Call::TripleClick { x, y })
+    }
+
+    // Keyboard actions
+
+    fn hold_key(&mut self, key: &str, duration: Duration) -> Result<()> {
+        check_key_combination(key)?;
+        self.record(Call::HoldKey { key: key.to_string(), duration })
+    }
+
+    fn key_down(&mut self, key_combination: &str) -> Result<()> {
+        check_key_combination(key_combination)?;
+        self.record(Call::KeyDown(key_combination.to_string()))
+    }
+
+    fn key_up(&mut self, key_combination: &str) -> Result<()> {
+        check_key_combination(key_combination)?;
+        self.record(Call::KeyUp(key_combination.to_string()))
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.record(Call::TypeText(text.to_string()))
+    }
+
+    fn press_key(&mut self, key_combination: &str) -> Result<()> {
+        check_key_combination(key_combination)?;
+        self.record(Call::PressKey(key_combination.to_string()))
+    }
+}
+
+/// Fill a rectangle of the image, clamped to its bounds
+pub fn fill(image: &mut RgbImage, (x, y, width, height): Rect, color: Rgb<u8>) {
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            image.put_pixel(px, py, color);
+        }
+    }
+}
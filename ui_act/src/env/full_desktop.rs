@@ -1,23 +1,27 @@
 use anyhow::{anyhow, Result};
 use image::DynamicImage;
 use xcap::Monitor;
+use x11rb::connection::Connection;
 use crate::env::ComputerEnvironment;
 use crate::utils::get_first_monitor;
-use crate::input::MPXInput;
+use crate::input::{create_input, InputBackend, InputKind};
 use crate::device::{MouseButton, ScrollDirection};
 use std::thread;
 use std::time::Duration;
 
 pub struct FullDesktopEnvironment {
-    input: MPXInput,
-    monitor: Monitor
+    input: Box<dyn InputBackend>,
+    monitor: Monitor,
+    root: u32
 }
 
 impl FullDesktopEnvironment {
-    pub fn create() -> Result<Self> {
+    pub fn create(input: InputKind) -> Result<Self> {
         // TODO: Probably needs to be the upper left most monitor as we don't handle monitor offsets
         let monitor = get_first_monitor()?;
-        Ok(FullDesktopEnvironment { input: MPXInput::create(&monitor)?, monitor: monitor })
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen_num].root;
+        Ok(FullDesktopEnvironment { input: create_input(input, &monitor)?, monitor, root })
     }
 }
 
@@ -48,64 +52,72 @@ impl ComputerEnvironment for FullDesktopEnvironment {
     }
 
     fn scroll(&mut self, direction: &str, amount: u32) -> Result<()> {
-        self.input.mouse.scroll(ScrollDirection::from_str(direction)?, amount)
+        self.input.scroll(ScrollDirection::from_str(direction)?, amount)
     }
 
     // Mouse Actions
 
     fn mouse_move(&mut self, x: u32, y: u32) -> Result<()> {
-        self.input.mouse.mouse_move(x, y)
+        self.input.mouse_move(x, y)
     }
 
     fn cursor_position(&mut self) -> Result<(u32, u32)> {
-        // self.input.mouse.cursor_position()
-        Ok((0, 0))
+        let (x, y) = self.input.cursor_position(self.root)?;
+        Ok((x.max(0) as u32, y.max(0) as u32))
     }
 
     fn left_mouse_down(&mut self) -> Result<()> {
-        self.input.mouse.mouse_down(MouseButton::Left)
+        self.input.mouse_down(MouseButton::Left)
     }
 
     fn left_mouse_up(&mut self) -> Result<()> {
-        self.input.mouse.mouse_up(MouseButton::Left)
+        self.input.mouse_up(MouseButton::Left)
     }
 
     fn left_click_drag(&mut self, x: u32, y: u32) -> Result<()> {
-        self.input.mouse.click_drag(MouseButton::Left, x, y)
+        self.input.click_drag(MouseButton::Left, x, y)
     }
 
     fn left_click(&mut self) -> Result<()> {
-        self.input.mouse.click(MouseButton::Left)
+        self.input.click(MouseButton::Left)
     }
 
     fn right_click(&mut self) -> Result<()> {
-        self.input.mouse.click(MouseButton::Right)
+        self.input.click(MouseButton::Right)
     }
 
     fn middle_click(&mut self) -> Result<()> {
-        self.input.mouse.click(MouseButton::Middle)
+        self.input.click(MouseButton::Middle)
     }
 
     fn double_click(&mut self) -> Result<()> {
-        self.input.mouse.double_click()
+        self.input.double_click()
     }
 
     fn triple_click(&mut self) -> Result<()> {
-        self.input.mouse.triple_click()
+        self.input.triple_click()
     }
 
     // Keyboard Actions
 
     fn hold_key(&mut self, key: &str, duration: Duration) -> Result<()> {
-        self.input.keyboard.hold_key(key, duration)
+        self.input.hold_key(key, duration)
+    }
+
+    fn key_down(&mut self, key_combination: &str) -> Result<()> {
+        self.input.keys_down(key_combination)
+    }
+
+    fn key_up(&mut self, key_combination: &str) -> Result<()> {
+        self.input.keys_up(key_combination)
     }
 
     fn type_text(&mut self, text: &str) -> Result<()> {
-        self.input.keyboard.type_text(text)
+        self.input.type_text(text)
     }
 
     fn press_key(&mut self, key_combination: &str) -> Result<()> {
-        self.input.keyboard.press_key(key_combination)
+        self.input.press_key(key_combination)
     }
 
 }
\ No newline at end of file
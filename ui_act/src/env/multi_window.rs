@@ -0,0 +1,289 @@
+use anyhow::{anyhow, Context, Result};
+use image::{DynamicImage, Rgb, RgbImage};
+use std::time::Duration;
+use xcap::Monitor;
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt};
+use x11rb::connection::Connection;
+use x11rb::rust_connection::RustConnection;
+
+use crate::input::{create_input, InputBackend, InputKind};
+use crate::env::ComputerEnvironment;
+use crate::env::single_window::{get_xwindow_name, set_always_on_top};
+use crate::utils::get_first_monitor;
+use crate::device::{MouseButton, ScrollDirection};
+
+/// Space between the windows in the screenshot
+const GAP: u32 = 10;
+const BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+
+/// A window's position on the screen and its size
+struct Placement {
+    root_x: i32,
+    root_y: i32,
+    width: u32,
+    height: u32,
+}
+
+/// Several windows shown to the agent as one screen. The screenshot has the windows side by side,
+/// left to right in the given order, and coordinates are mapped back to the window they fall in.
+/// All windows are kept on top, and actions outside of them are refused. The windows shouldn't
+/// overlap on the real screen, since they are captured from it.
+pub struct MultiWindowEnvironment {
+    input: Box<dyn InputBackend>,
+    monitor: Monitor,
+    xwindow_ids: Vec<u32>,
+    xconn: RustConnection,
+    root: u32,
+}
+
+impl MultiWindowEnvironment {
+    pub fn create(xwindow_ids: Vec<u32>, input: InputKind) -> Result<Self> {
+        if xwindow_ids.is_empty() {
+            return Err(anyhow!("No windows given"));
+        }
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen_num].root;
+        let env = MultiWindowEnvironment {
+            input: create_input(input, &get_first_monitor()?)?,
+            monitor: get_first_monitor()?,
+            xwindow_ids,
+            xconn: conn,
+            root,
+        };
+        for window in &env.xwindow_ids {
+            set_always_on_top(&env.xconn, *window, true)
+                .with_context(|| format!("Failed to keep window {} on top", window))?;
+        }
+        Ok(env)
+    }
+
+    fn placements(&self) -> Result<Vec<Placement>> {
+        self.xwindow_ids.iter().map(|window| {
+            let geom = self.xconn.get_geometry(*window)?.reply()
+                .with_context(|| format!("Failed to get the geometry of window {}", window))?;
+            // The geometry is relative the parent, which is the frame of a managed window
+            let origin = self.xconn.translate_coordinates(*window, self.root, 0, 0)?.reply()?;
+            Ok(Placement { root_x: origin.dst_x as i32, root_y: origin.dst_y as i32, width: geom.width as u32, height: geom.height as u32 })
+        }).collect()
+    }
+
+    /// Screen position of a point in the composite screen
+    fn to_root(&self, x: u32, y: u32) -> Result<(u32, u32)> {
+        let placements = self.placements()?;
+        let sizes = sizes(&placements);
+        let (index, wx, wy) = locate(&sizes, x, y)
+            .ok_or_else(|| anyhow!("Mouse coordinates ({}, {}) are not inside any of the windows", x, y))?;
+        let placement = &placements[index];
+        Ok(((placement.root_x + wx as i32).max(0) as u32, (placement.root_y + wy as i32).max(0) as u32))
+    }
+
+    /// Refuse button actions while the pointer is outside the windows, where it may be at startup
+    fn check_pointer(&mut self) -> Result<()> {
+        self.cursor_position().map(|_| ()).context("Move the mouse into one of the windows first")
+    }
+}
+
+/// Find the top level windows whose title or class contains `pattern`, ignoring case
+pub fn find_windows(pattern: &str) -> Result<Vec<u32>> {
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen_num].root;
+    let client_list = conn.intern_atom(false, b"_NET_CLIENT_LIST")?.reply()?.atom;
+    let reply = conn.get_property(false, root, client_list, AtomEnum::WINDOW, 0, 4096)?.reply()
+        .context("Failed to get _NET_CLIENT_LIST property")?;
+    let windows = reply.value32().ok_or_else(|| anyhow!("The window manager doesn't support _NET_CLIENT_LIST"))?;
+
+    let pattern = pattern.to_lowercase();
+    let mut matching = vec![];
+    for window in windows {
+        let name = get_xwindow_name(&conn, window).unwrap_or_default();
+        let class = conn.get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 1024)?.reply()?;
+        // WM_CLASS is the instance and class names, null separated
+        let class = String::from_utf8_lossy(&class.value).replace('\0', " ");
+        if name.to_lowercase().contains(&pattern) || class.to_lowercase().contains(&pattern) {
+            matching.push(window);
+        }
+    }
+    Ok(matching)
+}
+
+fn sizes(placements: &[Placement]) -> Vec<(u32, u32)> {
+    placements.iter().map(|p| (p.width, p.height)).collect()
+}
+
+/// Horizontal offset of each window in the composite screen
+fn offsets(sizes: &[(u32, u32)]) -> Vec<u32> {
+    sizes.iter().scan(0, |x, (width, _)| {
+        let offset = *x;
+        *x += width + GAP;
+        Some(offset)
+    }).collect()
+}
+
+/// The window at a point of the composite screen, and the point relative that window
+fn locate(sizes: &[(u32, u32)], x: u32, y: u32) -> Option<(usize, u32, u32)> {
+    offsets(sizes).into_iter().zip(sizes).enumerate()
+        .find(|(_, (offset, (width, height)))| x >= *offset && x < offset + width && y < *height)
+        .map(|(index, (offset, _))| (index, x - offset, y))
+}
+
+fn composite_size(sizes: &[(u32, u32)]) -> (u32, u32) {
+    let width = sizes.iter().map(|(width, _)| width).sum::<u32>() + GAP * (sizes.len() as u32).saturating_sub(1);
+    let height = sizes.iter().map(|(_, height)| *height).max().unwrap_or(0);
+    (width, height)
+}
+
+impl ComputerEnvironment for MultiWindowEnvironment {
+    fn name(&self) -> String {
+        let names: Vec<String> = self.xwindow_ids.iter()
+            .map(|window| get_xwindow_name(&self.xconn, *window).unwrap_or_else(|_| "Unknown window name".to_string()))
+            .collect();
+        names.join(" | ")
+    }
+
+    fn width(&self) -> Result<u32> {
+        Ok(composite_size(&sizes(&self.placements()?)).0)
+    }
+
+    fn height(&self) -> Result<u32> {
+        Ok(composite_size(&sizes(&self.placements()?)).1)
+    }
+
+    fn screenshot(&self) -> Result<RgbImage> {
+        let placements = self.placements()?;
+        let sizes = sizes(&placements);
+        let (width, height) = composite_size(&sizes);
+
+        let screen = self.monitor.capture_image()?;
+        let mut composite = RgbImage::from_pixel(width, height, BACKGROUND);
+        for (placement, offset) in placements.iter().zip(offsets(&sizes)) {
+            // Parts of the window off the screen are left as background
+            let (x, y) = (placement.root_x.max(0) as u32, placement.root_y.max(0) as u32);
+            let (skip_x, skip_y) = (x as i32 - placement.root_x, y as i32 - placement.root_y);
+            let window = image::imageops::crop_imm(&screen, x, y, placement.width.saturating_sub(skip_x as u32), placement.height.saturating_sub(skip_y as u32)).to_image();
+            let window = DynamicImage::ImageRgba8(window).to_rgb8();
+            image::imageops::replace(&mut composite, &window, (offset as i32 + skip_x) as i64, skip_y as i64);
+        }
+        Ok(composite)
+    }
+
+    fn wait(&mut self, duration: Duration) -> Result<()> {
+        std::thread::sleep(duration);
+        Ok(())
+    }
+
+    fn scroll(&mut self, direction: &str, amount: u32) -> Result<()> {
+        self.check_pointer()?;
+        self.input.scroll(ScrollDirection::from_str(direction)?, amount)
+    }
+
+    // Mouse actions
+
+    fn mouse_move(&mut self, x: u32, y: u32) -> Result<()> {
+        let (x, y) = self.to_root(x, y)?;
+        self.input.mouse_move(x, y)
+    }
+
+    fn cursor_position(&mut self) -> Result<(u32, u32)> {
+        let (x, y) = self.input.cursor_position(self.root)?;
+        let placements = self.placements()?;
+        let offsets = offsets(&sizes(&placements));
+        placements.iter().zip(offsets)
+            .find_map(|(p, offset)| {
+                let (wx, wy) = (x - p.root_x, y - p.root_y);
+                let inside = wx >= 0 && wy >= 0 && wx < p.width as i32 && wy < p.height as i32;
+                inside.then_some((offset + wx as u32, wy as u32))
+            })
+            .ok_or_else(|| anyhow!("Cursor at ({}, {}) is outside the windows", x, y))
+    }
+
+    fn left_mouse_down(&mut self) -> Result<()> {
+        self.check_pointer()?;
+        self.input.mouse_down(MouseButton::Left)
+    }
+
+    fn left_mouse_up(&mut self) -> Result<()> {
+        self.input.mouse_up(MouseButton::Left)
+    }
+
+    fn left_click(&mut self) -> Result<()> {
+        self.check_pointer()?;
+        self.input.click(MouseButton::Left)
+    }
+
+    fn left_click_drag(&mut self, x: u32, y: u32) -> Result<()> {
+        self.check_pointer()?;
+        let (x, y) = self.to_root(x, y)?;
+        self.input.click_drag(MouseButton::Left, x, y)
+    }
+
+    fn right_click(&mut self) -> Result<()> {
+        self.check_pointer()?;
+        self.input.click(MouseButton::Right)
+    }
+
+    fn middle_click(&mut self) -> Result<()> {
+        self.check_pointer()?;
+        self.input.click(MouseButton::Middle)
+    }
+
+    fn double_click(&mut self) -> Result<()> {
+        self.check_pointer()?;
+        self.input.double_click()
+    }
+
+    fn triple_click(&mut self) -> Result<()> {
+        self.check_pointer()?;
+        self.input.triple_click()
+    }
+
+    // Keyboard actions
+
+    fn hold_key(&mut self, key: &str, duration: Duration) -> Result<()> {
+        self.input.hold_key(key, duration)
+    }
+
+    fn key_down(&mut self, key_combination: &str) -> Result<()> {
+        self.input.keys_down(key_combination)
+    }
+
+    fn key_up(&mut self, key_combination: &str) -> Result<()> {
+        self.input.keys_up(key_combination)
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.input.type_text(text)
+    }
+
+    fn press_key(&mut self, key_combination: &str) -> Result<()> {
+        self.input.press_key(key_combination)
+    }
+}
+
+impl Drop for MultiWindowEnvironment {
+    fn drop(&mut self) {
+        for window in &self.xwindow_ids {
+            let _ = set_always_on_top(&self.xconn, *window, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_composite_coordinates_to_windows() {
+        let sizes = [(300, 200), (400, 100), (100, 250)];
+        assert_eq!(composite_size(&sizes), (820, 250));
+        assert_eq!(offsets(&sizes), [0, 310, 720]);
+
+        assert_eq!(locate(&sizes, 0, 0), Some((0, 0, 0)));
+        assert_eq!(locate(&sizes, 299, 199), Some((0, 299, 199)));
+        assert_eq!(locate(&sizes, 310, 50), Some((1, 0, 50)));
+        assert_eq!(locate(&sizes, 819, 249), Some((2, 99, 249)));
+        // In the gap, below a shorter window and outside the composite
+        assert_eq!(locate(&sizes, 305, 10), None);
+        assert_eq!(locate(&sizes, 400, 150), None);
+        assert_eq!(locate(&sizes, 820, 0), None);
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use x11rb::connection::Connection;
+use x11rb::rust_connection::RustConnection;
+use crate::env::ComputerEnvironment;
+use crate::device::{MouseButton, ScrollDirection};
+use crate::utils::capture_x11_window;
+use crate::input::InputBackend;
+use crate::xtest::XTestInput;
+
+/// How long to wait for the X server to accept connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Display numbers to try, high enough not to collide with the user's own displays
+const DISPLAY_NUMBERS: std::ops::Range<u32> = 99..200;
+
+pub enum DisplayServer {
+    /// A virtual framebuffer without any visible window
+    Xvfb,
+    /// A nested X server in a window on the current display, so the agent can be watched
+    Xephyr,
+}
+
+impl DisplayServer {
+    pub fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "xvfb" => Ok(DisplayServer::Xvfb),
+            "xephyr" => Ok(DisplayServer::Xephyr),
+            _ => Err(anyhow!("Unknown display server: {}", name)),
+        }
+    }
+
+    fn command(&self, display: &str, width: u32, height: u32) -> Command {
+        let mut command = match self {
+            DisplayServer::Xvfb => {
+                let mut command = Command::new("Xvfb");
+                command.args([display, "-screen", "0", &format!("{}x{}x24", width, height)]);
+                command
+            }
+            DisplayServer::Xephyr => {
+                let mut command = Command::new("Xephyr");
+                command.args([display, "-screen", &format!("{}x{}", width, height), "-title", "UI Act"]);
+                command
+            }
+        };
+        command.args(["-nolisten", "tcp"]).stdout(Stdio::null()).stderr(Stdio::null());
+        command
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            DisplayServer::Xvfb => "Xvfb",
+            DisplayServer::Xephyr => "Xephyr",
+        }
+    }
+}
+
+/// A desktop on an X server of its own, started for the session and stopped when dropped.
+/// The agent works in the background without taking over the user's pointer and keyboard,
+/// and without any display at all when using Xvfb. Input is injected with XTest.
+pub struct NestedDisplayEnvironment {
+    server: Child,
+    app: Option<Child>,
+    display: String,
+    input: XTestInput,
+    xconn: RustConnection,
+    root: u32,
+}
+
+impl NestedDisplayEnvironment {
+    /// Start a `width`x`height` display, and run `command` in it if given
+    pub fn create(server: DisplayServer, width: u32, height: u32, command: Option<&str>) -> Result<Self> {
+        let number = DISPLAY_NUMBERS.clone()
+            .find(|n| !Path::new(&format!("/tmp/.X{}-lock", n)).exists() && !Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists())
+            .ok_or_else(|| anyhow!("No free X display number"))?;
+        let display = format!(":{}", number);
+        let mut child = server.command(&display, width, height).spawn()
+            .with_context(|| format!("Failed to start {}, is it installed?", server.name()))?;
+
+        let connected = wait_for_display(&mut child, &display, number)
+            .and_then(|xconn| Ok((xconn, XTestInput::create(Some(&display))?)));
+        let (xconn, input) = match connected {
+            Ok(connected) => connected,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e.context(format!("{} did not start", server.name())));
+            }
+        };
+        let root = xconn.setup().roots[0].root;
+        // From here on the server is stopped on drop
+        let mut env = NestedDisplayEnvironment { server: child, app: None, display, input, xconn, root };
+        println!("Started {} on display {}", server.name(), env.display);
+
+        if let Some(command) = command {
+            // exec, so that killing the child stops the application rather than the shell
+            let app = Command::new("sh").args(["-c", &format!("exec {}", command)]).env("DISPLAY", &env.display).spawn()
+                .with_context(|| format!("Failed to launch {}", command))?;
+            env.app = Some(app);
+        }
+        Ok(env)
+    }
+}
+
+/// Wait for the server to create its socket and accept a connection
+fn wait_for_display(server: &mut Child, display: &str, number: u32) -> Result<RustConnection> {
+    let socket = format!("/tmp/.X11-unix/X{}", number);
+    let started = Instant::now();
+    loop {
+        if let Some(status) = server.try_wait()? {
+            return Err(anyhow!("The server exited with {}", status));
+        }
+        if Path::new(&socket).exists()
+            && let Ok((xconn, _)) = x11rb::connect(Some(display)) {
+            return Ok(xconn);
+        }
+        if started.elapsed() > STARTUP_TIMEOUT {
+            return Err(anyhow!("Timed out waiting for display {}", display));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+impl ComputerEnvironment for NestedDisplayEnvironment {
+    fn name(&self) -> String {
+        "nested desktop".to_string()
+    }
+
+    fn width(&self) -> Result<u32> {
+        Ok(self.xconn.setup().roots[0].width_in_pixels as u32)
+    }
+
+    fn height(&self) -> Result<u32> {
+        Ok(self.xconn.setup().roots[0].height_in_pixels as u32)
+    }
+
+    fn screenshot(&self) -> Result<image::RgbImage> {
+        capture_x11_window(&self.xconn, self.root)
+    }
+
+    fn wait(&mut self, duration: Duration) -> Result<()> {
+        thread::sleep(duration);
+        Ok(())
+    }
+
+    fn scroll(&mut self, direction: &str, amount: u32) -> Result<()> {
+        self.input.scroll(ScrollDirection::from_str(direction)?, amount)
+    }
+
+    // Mouse Actions
+
+    fn mouse_move(&mut self, x: u32, y: u32) -> Result<()> {
+        self.input.mouse_move(x, y)
+    }
+
+    fn cursor_position(&mut self) -> Result<(u32, u32)> {
+        let (x, y) = self.input.cursor_position(self.root)?;
+        Ok((x.max(0) as u32, y.max(0) as u32))
+    }
+
+    fn left_mouse_down(&mut self) -> Result<()> {
+        self.input.mouse_down(MouseButton::Left)
+    }
+
+    fn left_mouse_up(&mut self) -> Result<()> {
+        self.input.mouse_up(MouseButton::Left)
+    }
+
+    fn left_click_drag(&mut self, x: u32, y: u32) -> Result<()> {
+        self.input.click_drag(MouseButton::Left, x, y)
+    }
+
+    fn left_click(&mut self) -> Result<()> {
+        self.input.click(MouseButton::Left)
+    }
+
+    fn right_click(&mut self) -> Result<()> {
+        self.input.click(MouseButton::Right)
+    }
+
+    fn middle_click(&mut self) -> Result<()> {
+        self.input.click(MouseButton::Middle)
+    }
+
+    fn double_click(&mut self) -> Result<()> {
+        self.input.double_click()
+    }
+
+    fn triple_click(&mut self) -> Result<()> {
+        self.input.triple_click()
+    }
+
+    // Keyboard Actions
+
+    fn hold_key(&mut self, key: &str, duration: Duration) -> Result<()> {
+        self.input.hold_key(key, duration)
+    }
+
+    fn key_down(&mut self, key_combination: &str) -> Result<()> {
+        self.input.keys_down(key_combination)
+    }
+
+    fn key_up(&mut self, key_combination: &str) -> Result<()> {
+        self.input.keys_up(key_combination)
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.input.type_text(text)
+    }
+
+    fn press_key(&mut self, key_combination: &str) -> Result<()> {
+        self.input.press_key(key_combination)
+    }
+}
+
+impl Drop for NestedDisplayEnvironment {
+    fn drop(&mut self) {
+        if let Some(app) = &mut self.app {
+            let _ = app.kill();
+            let _ = app.wait();
+        }
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+    }
+}
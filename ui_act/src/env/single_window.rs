@@ -3,27 +3,28 @@ use image::DynamicImage;
 use xcap::Monitor;
 use x11rb::protocol::xproto::{ConnectionExt, *};
 use x11rb::connection::Connection;
+use x11rb::rust_connection::RustConnection;
 
-use crate::input::MPXInput;
+use crate::input::{create_input, InputBackend, InputKind};
 use crate::env::ComputerEnvironment;
 use crate::utils::get_first_monitor;
 use crate::device::{MouseButton, ScrollDirection};
 use std::time::Duration;
 
 pub struct SingleWindowEnvironment {
-    input: MPXInput,
+    input: Box<dyn InputBackend>,
     monitor: Monitor,
     xwindow_id: u32,
-    xconn: x11rb::rust_connection::RustConnection
+    xconn: RustConnection
 }
 
 impl SingleWindowEnvironment {
-    pub fn create(xwindow_id: u32) -> Result<Self> {
+    pub fn create(xwindow_id: u32, input: InputKind) -> Result<Self> {
         let (conn, _screen_num) = x11rb::connect(None)?;
         
         // Set window to always be on top
         let env = SingleWindowEnvironment { 
-            input: MPXInput::create(&get_first_monitor()?)?, 
+            input: create_input(input, &get_first_monitor()?)?, 
             monitor: get_first_monitor()?, 
             xwindow_id: xwindow_id,
             xconn: conn 
@@ -35,58 +36,67 @@ impl SingleWindowEnvironment {
     }
     
     fn set_always_on_top(&self, on_top: bool) -> Result<()> {
-        // Set window state to always on top using _NET_WM_STATE_ABOVE
-        let atom_above = self.xconn.intern_atom(false, b"_NET_WM_STATE_ABOVE")?.reply()?.atom;
-        let atom_wm_state = self.xconn.intern_atom(false, b"_NET_WM_STATE")?.reply()?.atom;
-        
-        let action = if on_top { 1 } else { 0 }; // 1 = add, 0 = remove
-        
-        let event = ClientMessageEvent::new(
-            32, // format
-            self.xwindow_id,
-            atom_wm_state,
-            [action, atom_above, 0, 1, 0],
-        );
-        
-        // Get the root window
-        let setup = self.xconn.setup();
-        let root = setup.roots[0].root;
-        
-        self.xconn.send_event(
-            false,
-            root,
-            EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY,
-            event,
-        )?;
-        
-        self.xconn.flush()?;
-
-        // Sleep for 500ms to allow the window manager to process the always-on-top request
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        
-        Ok(())
+        set_always_on_top(&self.xconn, self.xwindow_id, on_top)
     }
 
     fn get_xwindow_name(&self) -> Result<String> {
-        // Try to get the window name using X11
-        let reply = self.xconn.get_property(
-            false,
-            self.xwindow_id,
-            self.xconn.intern_atom(false, b"_NET_WM_NAME")?.reply()?.atom,
-            self.xconn.intern_atom(false, b"UTF8_STRING")?.reply()?.atom,
-            0,
-            1024,
-        )?.reply().context("Failed to get _NET_WM_NAME property")?;
-
-        if let Some(name_bytes) = reply.value.get(..) {
-            if let Ok(name) = std::str::from_utf8(name_bytes) {
-                return Ok(name.to_string());
-            } else {
-                return Err(anyhow::anyhow!("Failed to decode window name from _NET_WM_NAME property"));
-            }
+        get_xwindow_name(&self.xconn, self.xwindow_id)
+    }
+}
+
+/// Ask the window manager to keep `window` above other windows, or stop doing so
+pub fn set_always_on_top(conn: &RustConnection, window: u32, on_top: bool) -> Result<()> {
+    // Set window state to always on top using _NET_WM_STATE_ABOVE
+    let atom_above = conn.intern_atom(false, b"_NET_WM_STATE_ABOVE")?.reply()?.atom;
+    let atom_wm_state = conn.intern_atom(false, b"_NET_WM_STATE")?.reply()?.atom;
+
+    let action = if on_top { 1 } else { 0 }; // 1 = add, 0 = remove
+
+    let event = ClientMessageEvent::new(
+        32, // format
+        window,
+        atom_wm_state,
+        [action, atom_above, 0, 1, 0],
+    );
+
+    // Get the root window
+    let setup = conn.setup();
+    let root = setup.roots[0].root;
+
+    conn.send_event(
+        false,
+        root,
+        EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY,
+        event,
+    )?;
+
+    conn.flush()?;
+
+    // Sleep for 500ms to allow the window manager to process the always-on-top request
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    Ok(())
+}
+
+pub fn get_xwindow_name(conn: &RustConnection, window: u32) -> Result<String> {
+    // Try to get the window name using X11
+    let reply = conn.get_property(
+        false,
+        window,
+        conn.intern_atom(false, b"_NET_WM_NAME")?.reply()?.atom,
+        conn.intern_atom(false, b"UTF8_STRING")?.reply()?.atom,
+        0,
+        1024,
+    )?.reply().context("Failed to get _NET_WM_NAME property")?;
+
+    if let Some(name_bytes) = reply.value.get(..) {
+        if let Ok(name) = std::str::from_utf8(name_bytes) {
+            return Ok(name.to_string());
         } else {
-            return Err(anyhow::anyhow!("Failed to get window name from _NET_WM_NAME property"));
+            return Err(anyhow::anyhow!("Failed to decode window name from _NET_WM_NAME property"));
         }
+    } else {
+        return Err(anyhow::anyhow!("Failed to get window name from _NET_WM_NAME property"));
     }
 }
 
@@ -100,6 +110,10 @@ impl ComputerEnvironment for SingleWindowEnvironment {
         }
     }
 
+    fn window_title(&self) -> Option<String> {
+        self.get_xwindow_name().ok()
+    }
+
     fn width(&self) -> Result<u32> {
         // Window resolution in framebuffer scale
         let geom = self.xconn.get_geometry(self.xwindow_id)?.reply()?;
@@ -130,43 +144,49 @@ impl ComputerEnvironment for SingleWindowEnvironment {
         if x >= geom.width as u32 || y >= geom.height as u32 {
             return Err(anyhow::anyhow!("Mouse coordinates ({}, {}) exceed window dimensions ({}x{})", x, y, geom.width, geom.height));
         }
-        self.input.mouse.mouse_move(geom.x as u32 + x, geom.y as u32 + y)
+        self.input.mouse_move(geom.x as u32 + x, geom.y as u32 + y)
     }
 
     fn cursor_position(&mut self) -> Result<(u32, u32)> {
-        Ok((0, 0)) // todo: implement
+        // Relative the window, like mouse_move
+        let (x, y) = self.input.cursor_position(self.xwindow_id)?;
+        let geom = self.xconn.get_geometry(self.xwindow_id)?.reply()?;
+        if x < 0 || y < 0 || x >= geom.width as i32 || y >= geom.height as i32 {
+            return Err(anyhow::anyhow!("Cursor at ({}, {}) is outside the window ({}x{})", x, y, geom.width, geom.height));
+        }
+        Ok((x as u32, y as u32))
     }
 
     fn left_mouse_down(&mut self) -> Result<()> {
-        self.input.mouse.mouse_down(MouseButton::Left)
+        self.input.mouse_down(MouseButton::Left)
     }
 
     fn left_mouse_up(&mut self) -> Result<()> {
-        self.input.mouse.mouse_up(MouseButton::Left)
+        self.input.mouse_up(MouseButton::Left)
     }
 
     fn left_click_drag(&mut self, x: u32, y: u32) -> Result<()> {
-        self.input.mouse.click_drag(MouseButton::Left, x, y)
+        self.input.click_drag(MouseButton::Left, x, y)
     }
 
     fn left_click(&mut self) -> Result<()> {
-        self.input.mouse.click(MouseButton::Left)
+        self.input.click(MouseButton::Left)
     }
 
     fn right_click(&mut self) -> Result<()> {
-        self.input.mouse.click(MouseButton::Right)
+        self.input.click(MouseButton::Right)
     }
 
     fn middle_click(&mut self) -> Result<()> {
-        self.input.mouse.click(MouseButton::Middle)
+        self.input.click(MouseButton::Middle)
     }
 
     fn double_click(&mut self) -> Result<()> {
-        self.input.mouse.double_click()
+        self.input.double_click()
     }
 
     fn triple_click(&mut self) -> Result<()> {
-        self.input.mouse.triple_click()
+        self.input.triple_click()
     }
 
     fn wait(&mut self, duration: Duration) -> Result<()> {
@@ -175,19 +195,27 @@ impl ComputerEnvironment for SingleWindowEnvironment {
     }
 
     fn scroll(&mut self, direction: &str, amount: u32) -> Result<()> {
-        self.input.mouse.scroll(ScrollDirection::from_str(direction)?, amount)
+        self.input.scroll(ScrollDirection::from_str(direction)?, amount)
     }
 
     fn hold_key(&mut self, key: &str, duration: Duration) -> Result<()> {
-        self.input.keyboard.hold_key(key, duration)
+        self.input.hold_key(key, duration)
+    }
+
+    fn key_down(&mut self, key_combination: &str) -> Result<()> {
+        self.input.keys_down(key_combination)
+    }
+
+    fn key_up(&mut self, key_combination: &str) -> Result<()> {
+        self.input.keys_up(key_combination)
     }
 
     fn type_text(&mut self, text: &str) -> Result<()> {
-        self.input.keyboard.type_text(text)
+        self.input.type_text(text)
     }
 
     fn press_key(&mut self, key_combination: &str) -> Result<()> {
-        self.input.keyboard.press_key(key_combination)
+        self.input.press_key(key_combination)
     }
 
 }
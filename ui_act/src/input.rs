@@ -1,18 +1,85 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use std::time::Duration;
 use xcap::Monitor;
-use crate::device::{XInputMaster, MouseDevice, KeyboardDevice, run_xinput};
+use x11rb::connection::Connection;
+use x11rb::protocol::xinput::ConnectionExt;
+use x11rb::rust_connection::RustConnection;
+use crate::device::{XInputMaster, MouseButton, MouseDevice, KeyboardDevice, ScrollDirection, run_xinput};
+use crate::xtest::XTestInput;
+
+/// Name of the xinput master device that the agent acts through
+pub const MASTER_NAME: &str = "UI Act";
+
+/// A way of sending mouse and keyboard input to the X server. Coordinates are relative the root window.
+pub trait InputBackend {
+    fn mouse_move(&mut self, x: u32, y: u32) -> Result<()>;
+    fn scroll(&mut self, scroll_direction: ScrollDirection, amount: u32) -> Result<()>;
+    fn mouse_down(&mut self, button: MouseButton) -> Result<()>;
+    fn mouse_up(&mut self, button: MouseButton) -> Result<()>;
+    fn click(&mut self, button: MouseButton) -> Result<()>;
+    fn click_drag(&mut self, button: MouseButton, x: u32, y: u32) -> Result<()>;
+    fn double_click(&mut self) -> Result<()>;
+    fn triple_click(&mut self) -> Result<()>;
+
+    fn keys_down(&mut self, key_combination: &str) -> Result<()>;
+    fn keys_up(&mut self, key_combination: &str) -> Result<()>;
+    fn hold_key(&mut self, key_combination: &str, duration: Duration) -> Result<()>;
+    fn type_text(&mut self, text: &str) -> Result<()>;
+    fn press_key(&mut self, key_combination: &str) -> Result<()>;
+
+    /// Position of the pointer relative to `window`
+    fn cursor_position(&self, window: u32) -> Result<(i32, i32)>;
+}
+
+/// Which input backend to use
+#[derive(Debug, Clone, Copy, Default)]
+pub enum InputKind {
+    /// Virtual uinput devices attached to an xinput master
+    #[default]
+    Uinput,
+    /// The XTest extension, which needs no device permissions and also works in Xvfb
+    XTest,
+}
+
+impl InputKind {
+    pub fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "uinput" => Ok(InputKind::Uinput),
+            "xtest" => Ok(InputKind::XTest),
+            _ => Err(anyhow!("Unknown input backend: {}", name)),
+        }
+    }
+}
+
+/// Create the input backend for the current display
+pub fn create_input(kind: InputKind, monitor: &Monitor) -> Result<Box<dyn InputBackend>> {
+    match kind {
+        InputKind::Uinput => Ok(Box::new(MPXInput::create(monitor)?)),
+        InputKind::XTest => {
+            let mut input = XTestInput::create(None)?;
+            // Act through our own master so the user's pointer and keyboard are left alone.
+            // Only the nested display, which has no user, uses the core devices.
+            input.attach_master(MASTER_NAME).context("Failed to create an xinput master for XTest input")?;
+            Ok(Box::new(input))
+        }
+    }
+}
 
 
 pub struct MPXInput {
     master: XInputMaster,
-    pub mouse: MouseDevice,
-    pub keyboard: KeyboardDevice,
+    mouse: MouseDevice,
+    keyboard: KeyboardDevice,
+    conn: RustConnection,
     pub width: i32,
     pub height: i32,
 }
 
 impl MPXInput {
     pub fn create(monitor: &Monitor) -> Result<Self> {
+        let (conn, _screen_num) = x11rb::connect(None)?;
+        Self::init_xi2(&conn)?;
+
         // Note: Uses the screen resolution of the first monitor
         // Multiply by scale_factor to get framebuffer size
         let scale = monitor.scale_factor()?;
@@ -21,16 +88,92 @@ impl MPXInput {
         let mouse = MouseDevice::create("ui-act-mouse", width, height)?;
         let keyboard = KeyboardDevice::create("ui-act-keyboard")?;
         //println!("Created virtual mouse and keyboard");
-    
-        let master = XInputMaster::create("UI Act")?;
+
+        let master = XInputMaster::create(MASTER_NAME)?;
         //println!("Created master device pair: {} (pointer id={} keyboard id={})", master.name, master.pointer_id, master.keyboard_id);
-    
+
         run_xinput(&["reattach", &mouse.id.to_string(), &master.pointer_id.to_string()])?;
         //println!("Attached {} (id={}) to {} (id={})", mouse.name, mouse.id, master.name, master.pointer_id);
         run_xinput(&["reattach", &keyboard.id.to_string(), &master.keyboard_id.to_string()])?;
         //println!("Attached {} (id={}) to {} (id={})", keyboard.name, keyboard.id, master.name, master.keyboard_id);
-    
-        Ok(MPXInput { master, mouse, keyboard, width, height })
+
+        Ok(MPXInput { master, mouse, keyboard, conn, width, height })
+    }
+}
+
+impl MPXInput {
+    /// Announce XI2 support on a connection, which the server requires before any XI2 request
+    pub fn init_xi2<C: Connection>(conn: &C) -> Result<()> {
+        let version = conn.xinput_xi_query_version(2, 0)?.reply()?;
+        if version.major_version < 2 {
+            return Err(anyhow!("XInput2 is not supported by the X server"));
+        }
+        Ok(())
+    }
+}
+
+/// Position of a master pointer relative to `window`, from XI2 XIQueryPointer
+pub fn master_cursor_position<C: Connection>(conn: &C, master: &XInputMaster, window: u32) -> Result<(i32, i32)> {
+    let reply = conn.xinput_xi_query_pointer(window, master.pointer_id as u16)?.reply()?;
+    // Coordinates are 16.16 fixed point
+    Ok((reply.win_x >> 16, reply.win_y >> 16))
+}
+
+impl InputBackend for MPXInput {
+    fn mouse_move(&mut self, x: u32, y: u32) -> Result<()> {
+        self.mouse.mouse_move(x, y)
+    }
+
+    fn scroll(&mut self, scroll_direction: ScrollDirection, amount: u32) -> Result<()> {
+        self.mouse.scroll(scroll_direction, amount)
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) -> Result<()> {
+        self.mouse.mouse_down(button)
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) -> Result<()> {
+        self.mouse.mouse_up(button)
+    }
+
+    fn click(&mut self, button: MouseButton) -> Result<()> {
+        self.mouse.click(button)
+    }
+
+    fn click_drag(&mut self, button: MouseButton, x: u32, y: u32) -> Result<()> {
+        self.mouse.click_drag(button, x, y)
+    }
+
+    fn double_click(&mut self) -> Result<()> {
+        self.mouse.double_click()
+    }
+
+    fn triple_click(&mut self) -> Result<()> {
+        self.mouse.triple_click()
+    }
+
+    fn keys_down(&mut self, key_combination: &str) -> Result<()> {
+        self.keyboard.keys_down(key_combination)
+    }
+
+    fn keys_up(&mut self, key_combination: &str) -> Result<()> {
+        self.keyboard.keys_up(key_combination)
+    }
+
+    fn hold_key(&mut self, key_combination: &str, duration: Duration) -> Result<()> {
+        self.keyboard.hold_key(key_combination, duration)
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.keyboard.type_text(text)
+    }
+
+    fn press_key(&mut self, key_combination: &str) -> Result<()> {
+        self.keyboard.press_key(key_combination)
+    }
+
+    fn cursor_position(&self, window: u32) -> Result<(i32, i32)> {
+        master_cursor_position(&self.conn, &self.master, window)
     }
 }
 
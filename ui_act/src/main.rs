@@ -4,25 +4,65 @@ mod agent;
 mod env;
 mod utils;
 mod telemetry;
+mod config;
+mod session;
+mod trace;
+mod replay;
+mod xtest;
 
 use std::env as std_env;
 use std::io::{self, Write};
-use crate::agent::AnthropicAgent;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde_json::json;
+use crate::config::{AgentConfig, ClientConfig, Config, parse_header};
+use crate::agent::output::OutputSchema;
+use crate::agent::{Agent, AgentError, Message, ModelProvider, RunOutcome, StopReason, anthropic::AnthropicProvider, openai::OpenAIProvider};
+use crate::session::SessionStore;
+use crate::trace::{Trace, TraceHeader, TraceWriter, TRACE_VERSION};
+use crate::replay::{replay, Diverged, Interrupted, ReplayOptions};
 use crate::telemetry::post_telemetry;
-use crate::env::{ComputerEnvironment, full_desktop::FullDesktopEnvironment, single_window::SingleWindowEnvironment};
+use crate::input::InputKind;
+use crate::env::{ComputerEnvironment, dry_run::DryRunEnvironment, full_desktop::FullDesktopEnvironment, multi_window::{self, MultiWindowEnvironment}, nested_display::{DisplayServer, NestedDisplayEnvironment}, single_window::SingleWindowEnvironment};
 
 
 const DEFAULT_MODEL: &str = "claude-opus-4-6";
-const USAGE: &str = "Usage: ui-act [--window <window_id>] [--model <model_id>] [--no-telemetry] [--help] [--version] <prompt>";
+const DEFAULT_OPENAI_MODEL: &str = "gpt-4.1";
+const DEFAULT_NESTED_SIZE: (u32, u32) = (1280, 800);
+const USAGE: &str = "Usage: ui-act [--window <window_id> | --windows <id,id,...> | --windows-matching <text> | --nested <xvfb|xephyr> [--nested-size <width>x<height>] [--launch <command>]] [--input <uinput|xtest>] [--provider <anthropic|openai>] [--model <model_id>] [--base-url <url>] [--proxy <url>] [--ca-bundle <file>] [--header <name:value>]... [--max-retries <n>] [--max-screenshots <n>] [--max-actions <n>] [--max-api-calls <n>] [--max-total-tokens <n>] [--max-cost <usd>] [--timeout <secs>] [--final-screenshot <file>] [--system-prompt <text> | --system-prompt-file <file>] [--max-tokens <n>] [--thinking-budget <n>] [--non-interactive] [--output-schema <file>] [--output <file>] [--resume <session_id>] [--trace <dir>] [--dry-run] [--config <file>] [--no-telemetry] [--help] [--version] <prompt>
+       ui-act replay [--window <window_id> | --windows <id,id,...> | --windows-matching <text> | --nested <xvfb|xephyr> [--nested-size <width>x<height>] [--launch <command>]] [--input <uinput|xtest>] [--threshold <0-1>] [--no-delay] [--non-interactive] <trace_dir>";
 
+// Exit codes, so that scripts can tell why a run ended
+const EXIT_ERROR: i32 = 1;
+const EXIT_LIMIT_REACHED: i32 = 2;
+const EXIT_API_FAILURE: i32 = 3;
+const EXIT_ENVIRONMENT_FAILURE: i32 = 4;
+const EXIT_REPLAY_DIVERGED: i32 = 5;
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Set by --non-interactive or the config file, in which case errors don't wait for the user
+/// before exiting and the result is printed as JSON
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
 
 fn on_error(msg: &str) -> ! {
+    exit_with_error(msg, EXIT_ERROR)
+}
+
+fn exit_with_error(msg: &str, code: i32) -> ! {
+    report_error(msg);
+    std::process::exit(code);
+}
+
+/// Print an error and wait for the user to read it. Used instead of `exit_with_error` once the
+/// environment exists, so that it is dropped and cleaned up before exiting.
+fn report_error(msg: &str) {
     eprintln!("Error: {}", msg);
-    eprintln!("Press Enter to exit...");
-    let _ = io::stdout().flush();
-    let mut _input = String::new();
-    let _ = io::stdin().read_line(&mut _input);
-    std::process::exit(1);
+    if !NON_INTERACTIVE.load(Ordering::Relaxed) {
+        eprintln!("Press Enter to exit...");
+        let _ = io::stdout().flush();
+        let mut _input = String::new();
+        let _ = io::stdin().read_line(&mut _input);
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -54,9 +94,28 @@ fn get_signal_handler() -> tokio::task::JoinHandle<()> {
 async fn main() -> () {
     let mut args = std_env::args();
     let _exe = args.next(); // skip executable name
-    let mut window_id = None;
+    let mut args = args.peekable();
+    if args.peek().map(String::as_str) == Some("replay") {
+        args.next();
+        run_replay(args);
+    }
+    let mut env_options = EnvOptions::default();
     let mut send_telemetry: bool = true;
-    let mut model = DEFAULT_MODEL.to_string();
+    let mut provider = "anthropic".to_string();
+    let mut model = None;
+    let mut config_path = None;
+    let mut final_screenshot_path = None;
+    let mut output_schema_path = None;
+    let mut output_path = None;
+    let mut resume = None;
+    let mut trace_dir = None;
+    let mut dry_run = false;
+    let mut prompt = None;
+    let mut agent_overrides = AgentConfig {
+        max_screenshots: None,
+        ..Default::default()
+    };
+    let mut client_overrides = ClientConfig::default();
 
     while let Some(arg) = args.next() {
         match arg.as_str() {
@@ -68,60 +127,461 @@ async fn main() -> () {
                 println!("ui-act version {}", env!("CARGO_PKG_VERSION"));
                 std::process::exit(0);
             }
+            "--non-interactive" => {
+                agent_overrides.non_interactive = true;
+                NON_INTERACTIVE.store(true, Ordering::Relaxed);
+            }
             "--no-telemetry" => {
                 send_telemetry = false;
             }
-            "--window" => {
-                window_id = args.next().map(|id| id.parse::<u32>()).transpose()
-                    .unwrap_or_else(|_| { on_error("Unable to parse window as int")});
+            flag if parse_env_arg(flag, &mut args, &mut env_options) => {}
+            "--provider" => {
+                provider = args.next().unwrap_or_else(|| { on_error("--provider requires a provider name argument") });
             }
             "--model" => {
-                model = args.next().unwrap_or_else(|| { on_error("--model requires a model id argument") });
+                model = Some(args.next().unwrap_or_else(|| { on_error("--model requires a model id argument") }));
+            }
+            "--base-url" => {
+                client_overrides.base_url = Some(args.next().unwrap_or_else(|| { on_error("--base-url requires a url argument") }));
+            }
+            "--proxy" => {
+                client_overrides.proxy = Some(args.next().unwrap_or_else(|| { on_error("--proxy requires a url argument") }));
+            }
+            "--ca-bundle" => {
+                client_overrides.ca_bundle = Some(args.next().map(PathBuf::from).unwrap_or_else(|| { on_error("--ca-bundle requires a file argument") }));
+            }
+            "--header" => {
+                let header = args.next().unwrap_or_else(|| { on_error("--header requires a \"name: value\" argument") });
+                let (name, value) = parse_header(&header).unwrap_or_else(|e| { on_error(&e.to_string()) });
+                client_overrides.headers.insert(name, value);
+            }
+            "--max-retries" => {
+                client_overrides.max_retries = args.next().map(|n| n.parse::<u32>()).transpose()
+                    .unwrap_or_else(|_| { on_error("Unable to parse max retries as int")});
+            }
+            "--max-screenshots" => {
+                agent_overrides.max_screenshots = args.next().map(|n| n.parse::<usize>()).transpose().ok().flatten()
+                    .filter(|n| *n > 0)
+                    .or_else(|| { on_error("--max-screenshots requires a positive int argument") });
+            }
+            "--max-actions" => {
+                agent_overrides.max_actions = args.next().map(|n| n.parse::<u32>()).transpose()
+                    .unwrap_or_else(|_| { on_error("Unable to parse max actions as int")});
+            }
+            "--max-api-calls" => {
+                agent_overrides.max_api_calls = args.next().map(|n| n.parse::<u32>()).transpose()
+                    .unwrap_or_else(|_| { on_error("Unable to parse max API calls as int")});
+            }
+            "--max-total-tokens" => {
+                agent_overrides.max_total_tokens = args.next().map(|n| n.parse::<u64>()).transpose()
+                    .unwrap_or_else(|_| { on_error("Unable to parse max total tokens as int")});
+            }
+            "--max-cost" => {
+                agent_overrides.max_cost = args.next().map(|n| n.parse::<f64>()).transpose()
+                    .unwrap_or_else(|_| { on_error("Unable to parse max cost as a number")});
+            }
+            "--timeout" => {
+                agent_overrides.timeout = args.next().map(|n| n.parse::<u64>()).transpose()
+                    .unwrap_or_else(|_| { on_error("Unable to parse timeout as int")});
+            }
+            "--final-screenshot" => {
+                final_screenshot_path = Some(args.next().map(PathBuf::from).unwrap_or_else(|| { on_error("--final-screenshot requires a file argument") }));
+            }
+            "--system-prompt" => {
+                agent_overrides.system_prompt = Some(args.next().unwrap_or_else(|| { on_error("--system-prompt requires a text argument") }));
+            }
+            "--system-prompt-file" => {
+                let path = args.next().unwrap_or_else(|| { on_error("--system-prompt-file requires a file argument") });
+                agent_overrides.system_prompt = Some(std::fs::read_to_string(&path)
+                    .unwrap_or_else(|e| { on_error(&format!("Failed to read system prompt file {}: {}", path, e)) }));
+            }
+            "--max-tokens" => {
+                agent_overrides.max_tokens = args.next().map(|n| n.parse::<u32>()).transpose()
+                    .unwrap_or_else(|_| { on_error("Unable to parse max tokens as int")});
+            }
+            "--thinking-budget" => {
+                agent_overrides.thinking_budget = args.next().map(|n| n.parse::<u32>()).transpose()
+                    .unwrap_or_else(|_| { on_error("Unable to parse thinking budget as int")});
+            }
+            "--output-schema" => {
+                output_schema_path = Some(args.next().map(PathBuf::from).unwrap_or_else(|| { on_error("--output-schema requires a file argument") }));
+            }
+            "--output" => {
+                output_path = Some(args.next().map(PathBuf::from).unwrap_or_else(|| { on_error("--output requires a file argument") }));
+            }
+            "--resume" => {
+                resume = Some(args.next().unwrap_or_else(|| { on_error("--resume requires a session id argument") }));
+            }
+            "--trace" => {
+                trace_dir = Some(args.next().map(PathBuf::from).unwrap_or_else(|| { on_error("--trace requires a directory argument") }));
+            }
+            "--dry-run" => {
+                dry_run = true;
+            }
+            "--config" => {
+                config_path = Some(args.next().map(PathBuf::from).unwrap_or_else(|| { on_error("--config requires a file argument") }));
             }
             _ => {
                 // Collect all remaining arguments as the prompt
                 let mut prompt_parts = vec![arg];
                 prompt_parts.extend(args);
-                let prompt = prompt_parts.join(" ");
-                return run_with_prompt(prompt, window_id, model, send_telemetry).await;
+                prompt = Some(prompt_parts.join(" "));
+                break;
             }
         }
     }
-    
-    on_error("Missing required prompt argument");
+
+    // When resuming, the prompt is optional and added as a new message
+    let prompt = match (prompt, &resume) {
+        (Some(prompt), _) => prompt,
+        (None, Some(_)) => String::new(),
+        (None, None) => on_error("Missing required prompt argument")
+    };
+    let history = match &resume {
+        Some(session_id) => SessionStore::open(session_id).and_then(|store| store.load())
+            .unwrap_or_else(|e| { on_error(&format!("Failed to resume session {}: {:#}", session_id, e)) }),
+        None => vec![]
+    };
+    let config = Config::load(config_path.as_deref())
+        .unwrap_or_else(|e| { on_error(&format!("{:#}", e)) });
+    let mut agent_config = config.agent.clone();
+    agent_config.merge(agent_overrides);
+    // The flag sets this right away for earlier errors, the config file can only set it here
+    NON_INTERACTIVE.store(agent_config.non_interactive, Ordering::Relaxed);
+    let provider = create_provider(&provider, model, config, &agent_config, client_overrides)
+        .unwrap_or_else(|e| { on_error(&format!("{:#}", e)) });
+    let output_schema = output_schema_path.map(|path| OutputSchema::load(&path)).transpose()
+        .unwrap_or_else(|e| { on_error(&format!("{:#}", e)) });
+    let mut agent = Agent::create(provider, agent_config, output_schema);
+    if let Some(session_id) = resume {
+        agent.session_id = session_id;
+    }
+    let output = Output { final_screenshot_path, output_path, trace_dir, dry_run };
+    let code = run_with_prompt(prompt, history, env_options, agent, output, send_telemetry).await;
+    std::process::exit(code);
 }
 
-async fn run_with_prompt(prompt: String, window_id: Option<u32>, model: String, send_telemetry: bool) {
-    let signal_handle = get_signal_handler();
+fn create_provider(name: &str, model: Option<String>, config: Config, agent_config: &AgentConfig, client_overrides: ClientConfig) -> anyhow::Result<Box<dyn ModelProvider>> {
+    match name {
+        "anthropic" => {
+            let model = model.unwrap_or_else(|| DEFAULT_MODEL.to_string());
+            let mut client_config = config.anthropic;
+            client_config.merge(client_overrides);
+            Ok(Box::new(AnthropicProvider::create(model, &client_config, agent_config)?))
+        }
+        "openai" => {
+            let model = model.unwrap_or_else(|| DEFAULT_OPENAI_MODEL.to_string());
+            let mut client_config = config.openai;
+            client_config.merge(client_overrides);
+            Ok(Box::new(OpenAIProvider::create(model, &client_config, agent_config)?))
+        }
+        _ => anyhow::bail!("Unknown provider: {}", name)
+    }
+}
 
-    let agent = AnthropicAgent::create(model).await
-        .unwrap_or_else(|e| { on_error(&e.to_string()) });
-    let mut env: Box<dyn ComputerEnvironment> = match window_id {
-        Some(wid) => {
+/// Where to write the results of a session
+struct Output {
+    final_screenshot_path: Option<PathBuf>,
+    /// File for the result submitted according to the output schema, instead of stdout
+    output_path: Option<PathBuf>,
+    /// Directory to record a trace of the executed actions in
+    trace_dir: Option<PathBuf>,
+    /// Log the actions to the session directory instead of executing them
+    dry_run: bool,
+}
+
+/// Which environment to run in, from the command line
+#[derive(Default)]
+struct EnvOptions {
+    window_id: Option<u32>,
+    window_ids: Option<Vec<u32>>,
+    /// Text to select windows by title or class
+    windows_matching: Option<String>,
+    nested: Option<DisplayServer>,
+    nested_size: Option<(u32, u32)>,
+    /// Command to run in the nested display
+    launch: Option<String>,
+    input: InputKind,
+}
+
+/// Parse an environment flag and its value, returning false if `arg` isn't one
+fn parse_env_arg(arg: &str, args: &mut impl Iterator<Item = String>, options: &mut EnvOptions) -> bool {
+    match arg {
+        "--window" => {
+            options.window_id = args.next().map(|id| id.parse::<u32>()).transpose()
+                .unwrap_or_else(|_| { on_error("Unable to parse window as int")});
+        }
+        "--windows" => {
+            options.window_ids = args.next()
+                .and_then(|ids| ids.split(',').map(|id| id.trim().parse::<u32>().ok()).collect())
+                .or_else(|| { on_error("--windows requires a comma separated list of window ids") });
+        }
+        "--windows-matching" => {
+            options.windows_matching = Some(args.next().unwrap_or_else(|| { on_error("--windows-matching requires a text argument") }));
+        }
+        "--nested" => {
+            let server = args.next().unwrap_or_else(|| { on_error("--nested requires xvfb or xephyr") });
+            options.nested = Some(DisplayServer::from_str(&server).unwrap_or_else(|e| { on_error(&e.to_string()) }));
+        }
+        "--nested-size" => {
+            options.nested_size = args.next()
+                .and_then(|size| {
+                    let (width, height) = size.split_once('x')?;
+                    Some((width.parse().ok()?, height.parse().ok()?))
+                })
+                .or_else(|| { on_error("--nested-size requires a size like 1280x800") });
+        }
+        "--input" => {
+            let backend = args.next().unwrap_or_else(|| { on_error("--input requires uinput or xtest") });
+            options.input = InputKind::from_str(&backend).unwrap_or_else(|e| { on_error(&e.to_string()) });
+        }
+        "--launch" => {
+            options.launch = Some(args.next().unwrap_or_else(|| { on_error("--launch requires a command argument") }));
+        }
+        _ => return false
+    }
+    true
+}
+
+fn create_environment(options: EnvOptions) -> Box<dyn ComputerEnvironment> {
+    if options.nested.is_none() && (options.nested_size.is_some() || options.launch.is_some()) {
+        on_error("--nested-size and --launch require --nested");
+    }
+    let modes = [options.window_id.is_some(), options.window_ids.is_some(), options.windows_matching.is_some(), options.nested.is_some()];
+    if modes.iter().filter(|selected| **selected).count() > 1 {
+        on_error("Only one of --window, --windows, --windows-matching and --nested can be given");
+    }
+    let window_ids = match options.windows_matching {
+        Some(pattern) => {
+            let ids = multi_window::find_windows(&pattern)
+                .unwrap_or_else(|e| { exit_with_error(&e.to_string(), EXIT_ENVIRONMENT_FAILURE) });
+            if ids.is_empty() {
+                exit_with_error(&format!("No windows matching {:?}", pattern), EXIT_ENVIRONMENT_FAILURE);
+            }
+            Some(ids)
+        }
+        None => options.window_ids,
+    };
+    if let Some(ids) = window_ids {
+        println!("Running in multi window mode with window ids: {:?}", ids);
+        let env = MultiWindowEnvironment::create(ids, options.input)
+            .unwrap_or_else(|e| { exit_with_error(&format!("{:#}", e), EXIT_ENVIRONMENT_FAILURE) });
+        return Box::new(env);
+    }
+    match (options.window_id, options.nested) {
+        (Some(_), Some(_)) => on_error("--window and --nested can't be combined"),
+        (Some(wid), None) => {
             println!("Running in single window mode with window id: {}", wid);
-            let env = SingleWindowEnvironment::create(wid)
-                .unwrap_or_else(|e| { on_error(&e.to_string()) });
+            let env = SingleWindowEnvironment::create(wid, options.input)
+                .unwrap_or_else(|e| { exit_with_error(&format!("{:#}", e), EXIT_ENVIRONMENT_FAILURE) });
+            Box::new(env)
+        }
+        (None, Some(server)) => {
+            println!("Running in a nested display");
+            let (width, height) = options.nested_size.unwrap_or(DEFAULT_NESTED_SIZE);
+            let env = NestedDisplayEnvironment::create(server, width, height, options.launch.as_deref())
+                .unwrap_or_else(|e| { exit_with_error(&format!("{:#}", e), EXIT_ENVIRONMENT_FAILURE) });
             Box::new(env)
         }
-        _ => {
+        (None, None) => {
             println!("Running in full desktop mode");
-            let env = FullDesktopEnvironment::create()
-                .unwrap_or_else(|e| { on_error(&e.to_string()) });
+            let env = FullDesktopEnvironment::create(options.input)
+                .unwrap_or_else(|e| { exit_with_error(&format!("{:#}", e), EXIT_ENVIRONMENT_FAILURE) });
             Box::new(env)
         }
+    }
+}
+
+/// `ui-act replay`: execute the actions of a recorded trace again, without calling the model
+fn run_replay(mut args: impl Iterator<Item = String>) -> ! {
+    let mut env_options = EnvOptions::default();
+    let mut options = ReplayOptions { recorded_timing: true, threshold: None, interrupted: Default::default() };
+    let mut trace_dir = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" => {
+                eprintln!("{}", USAGE);
+                std::process::exit(0);
+            }
+            flag if parse_env_arg(flag, &mut args, &mut env_options) => {}
+            "--threshold" => {
+                options.threshold = args.next().and_then(|t| t.parse::<f64>().ok()).filter(|t| (0.0..=1.0).contains(t))
+                    .or_else(|| { on_error("--threshold requires a number between 0 and 1") });
+            }
+            "--no-delay" => {
+                options.recorded_timing = false;
+            }
+            "--non-interactive" => {
+                NON_INTERACTIVE.store(true, Ordering::Relaxed);
+            }
+            _ if trace_dir.is_none() => {
+                trace_dir = Some(PathBuf::from(arg));
+            }
+            _ => on_error(&format!("Unexpected argument: {}", arg))
+        }
+    }
+    let trace_dir = trace_dir.unwrap_or_else(|| { on_error("Missing required trace directory argument") });
+    let trace = Trace::load(&trace_dir)
+        .unwrap_or_else(|e| { on_error(&format!("{:#}", e)) });
+    println!("Replaying {} actions of session {}", trace.actions.len(), trace.header.session_id);
+
+    // Stop between actions on Ctrl-C, so that the environment is dropped and cleaned up
+    let interrupted = options.interrupted.clone();
+    ctrlc::set_handler(move || interrupted.store(true, Ordering::Relaxed))
+        .unwrap_or_else(|e| { on_error(&e.to_string()) });
+
+    let mut env = create_environment(env_options);
+    let code = match replay(env.as_mut(), &trace, &options) {
+        Ok(()) => {
+            println!("Replay completed");
+            0
+        }
+        Err(e) if e.is::<Interrupted>() => {
+            println!("Replay interrupted");
+            EXIT_INTERRUPTED
+        }
+        Err(e) if e.is::<Diverged>() => {
+            report_error(&e.to_string());
+            EXIT_REPLAY_DIVERGED
+        }
+        Err(e) => {
+            report_error(&format!("{:#}", e));
+            EXIT_ERROR
+        }
     };
+    drop(env);
+    std::process::exit(code);
+}
+
+/// Run the agent and return the exit code. The environment and agent are dropped on return,
+/// so the environment is always cleaned up before the process exits.
+async fn run_with_prompt(prompt: String, history: Vec<Message>, env_options: EnvOptions, mut agent: Agent, output: Output, send_telemetry: bool) -> i32 {
+    let signal_handle = get_signal_handler();
+
+    let non_interactive = NON_INTERACTIVE.load(Ordering::Relaxed);
+    let mut env = create_environment(env_options);
+    if output.dry_run {
+        let dry_run_env = SessionStore::open(&agent.session_id)
+            .map(|store| store.dir().join("dry-run"))
+            .and_then(|log_dir| Ok((DryRunEnvironment::create(env, &log_dir)?, log_dir)));
+        let (dry_run_env, log_dir) = match dry_run_env {
+            Ok(created) => created,
+            Err(e) => {
+                report_error(&format!("{:#}", e));
+                return EXIT_ERROR;
+            }
+        };
+        println!("Dry run: actions are logged to {} but not executed", log_dir.display());
+        env = Box::new(dry_run_env);
+    }
+    if let Some(dir) = &output.trace_dir {
+        match create_trace(dir, &agent, env.as_ref()) {
+            Ok(trace) => agent.set_trace(trace),
+            Err(e) => {
+                report_error(&format!("{:#}", e));
+                return EXIT_ERROR;
+            }
+        }
+    }
     if send_telemetry {
         post_telemetry(&agent.session_id, &env.name(), "session_start", None, None).await;
     }
 
     tokio::select! {
-        res = agent.run(&mut env, &prompt, send_telemetry) => {
-            res.unwrap_or_else(|e| { on_error(&e.to_string()) });
+        res = agent.run(&mut env, &prompt, history, send_telemetry) => {
+            agent.print_summary();
+            let (status, code) = match &res {
+                Ok(outcome) => match outcome.stop_reason {
+                    StopReason::Completed if agent.has_output_schema() && outcome.output.is_none() => {
+                        eprintln!("The session ended without a result");
+                        ("no_result", EXIT_ERROR)
+                    }
+                    StopReason::Completed => ("success", 0),
+                    StopReason::LimitReached(limit) => {
+                        println!("Session stopped early: {} reached", limit);
+                        ("limit_reached", EXIT_LIMIT_REACHED)
+                    }
+                },
+                Err(e) => match e.downcast_ref::<AgentError>() {
+                    Some(AgentError::Api(_)) => ("api_error", EXIT_API_FAILURE),
+                    Some(AgentError::Environment(_)) => ("environment_error", EXIT_ENVIRONMENT_FAILURE),
+                    None => ("error", EXIT_ERROR)
+                }
+            };
+            if let Ok(outcome) = &res {
+                if let Some(path) = &output.final_screenshot_path
+                    && let Err(e) = outcome.final_screenshot.save(path) {
+                    report_error(&format!("Failed to save final screenshot: {}", e));
+                    return EXIT_ERROR;
+                }
+                if let Some(result) = &outcome.output {
+                    match &output.output_path {
+                        Some(path) => {
+                            let json = serde_json::to_string_pretty(result).expect("JSON values always serialize");
+                            if let Err(e) = std::fs::write(path, json + "\n") {
+                                report_error(&format!("Failed to write output to {}: {}", path.display(), e));
+                                return EXIT_ERROR;
+                            }
+                        }
+                        // On a line of its own after all progress output, so it can be told apart.
+                        // In non-interactive mode it is part of the final result line instead.
+                        None if !non_interactive => println!("{}", result),
+                        None => {}
+                    }
+                }
+            }
+            if non_interactive {
+                print_result(&agent, status, res.as_ref());
+            }
+            if let Err(e) = res {
+                report_error(&e.to_string());
+            }
+            code
         }
         _ = signal_handle => {
+            agent.print_summary();
             if send_telemetry {
                 post_telemetry(&agent.session_id, &env.name(), "session_end", Some("interrupted"), Some(agent.action_count.get())).await;
             }
+            println!("Resume the session with: ui-act --resume {}", agent.session_id);
+            if non_interactive {
+                print_result(&agent, "interrupted", Err(&anyhow::anyhow!("Interrupted by signal")));
+                return EXIT_INTERRUPTED;
+            }
+            0
         }
     }
-}
\ No newline at end of file
+}
+
+fn create_trace(dir: &std::path::Path, agent: &Agent, env: &dyn ComputerEnvironment) -> anyhow::Result<TraceWriter> {
+    TraceWriter::create(dir, TraceHeader {
+        version: TRACE_VERSION,
+        session_id: agent.session_id.clone(),
+        model: agent.model().to_string(),
+        environment: env.name(),
+        screen_width: env.width()?,
+        screen_height: env.height()?,
+        started_at: chrono::Local::now().to_rfc3339(),
+    })
+}
+
+/// Print the result of a non-interactive run as a single line of JSON on stdout
+fn print_result(agent: &Agent, status: &str, res: Result<&RunOutcome, &anyhow::Error>) {
+    let usage = agent.usage.get();
+    let result = json!({
+        "status": status,
+        "session_id": agent.session_id,
+        "final_message": res.ok().and_then(|outcome| outcome.final_message.as_deref()),
+        "output": res.ok().and_then(|outcome| outcome.output.as_ref()),
+        "limit": res.ok().and_then(|outcome| match outcome.stop_reason {
+            StopReason::LimitReached(limit) => Some(limit.to_string()),
+            StopReason::Completed => None
+        }),
+        "error": res.err().map(|e| format!("{:#}", e)),
+        "action_count": agent.action_count.get(),
+        "api_calls": agent.api_calls.get(),
+        "usage": usage,
+        "estimated_cost_usd": agent.estimated_cost()
+    });
+    println!("{}", result);
+}
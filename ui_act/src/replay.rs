@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::agent::execute_action;
+use crate::env::ComputerEnvironment;
+use crate::trace::Trace;
+use crate::utils::{image_difference, img_shrink};
+
+/// Time for the UI to settle after an action, like the agent waits before its after screenshot
+const SETTLE_DELAY: Duration = Duration::from_millis(1000);
+
+pub struct ReplayOptions {
+    /// Wait between actions as in the recording, instead of only letting the UI settle after each
+    pub recorded_timing: bool,
+    /// Stop when a screenshot differs from the recorded one by more than this (0 to 1)
+    pub threshold: Option<f64>,
+    /// Set to stop the replay, e.g. on Ctrl-C
+    pub interrupted: Arc<AtomicBool>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Replay interrupted")]
+pub struct Interrupted;
+
+/// The screen no longer looks like it did when the trace was recorded
+#[derive(Debug, thiserror::Error)]
+#[error("Screen diverged from the trace after action {index} (difference {difference:.3} > threshold {threshold:.3})")]
+pub struct Diverged {
+    pub index: u32,
+    pub difference: f64,
+    pub threshold: f64,
+}
+
+/// Execute the actions of a trace in `env`, without calling the model. Failed actions of the
+/// recording are skipped, since they didn't change the environment.
+pub fn replay(env: &mut dyn ComputerEnvironment, trace: &Trace, options: &ReplayOptions) -> Result<()> {
+    let (width, height) = (env.width()?, env.height()?);
+    if (width, height) != (trace.header.screen_width, trace.header.screen_height) {
+        eprintln!(
+            "Warning: The environment is {}x{} but the trace was recorded at {}x{}, coordinates are scaled",
+            width, height, trace.header.screen_width, trace.header.screen_height
+        );
+    }
+
+    let actions: Vec<_> = trace.actions.iter().filter(|action| action.error.is_none()).collect();
+    let started = Instant::now();
+    for (i, action) in actions.iter().enumerate() {
+        if options.recorded_timing {
+            let at = Duration::from_millis(action.elapsed_ms - actions[0].elapsed_ms);
+            sleep(at.saturating_sub(started.elapsed()), options)?;
+        }
+        if options.interrupted.load(Ordering::Relaxed) {
+            return Err(Interrupted.into());
+        }
+        println!("[{}/{}] {:?}", i + 1, actions.len(), action.input);
+
+        // Coordinates are relative the recorded screenshots, map them to this environment
+        let (recorded_width, _) = image::image_dimensions(trace.dir.join(&action.screenshot_before))?;
+        let scale = recorded_width as f32 / width as f32;
+        if let Some(output) = execute_action(env, &action.input, scale)? {
+            println!("  {}", output);
+        }
+
+        // The recorded screenshot was taken once the UI settled, whatever the pause before the
+        // next action. The settle time counts towards that pause with recorded timings.
+        env.wait(SETTLE_DELAY)?;
+        if let (Some(threshold), Some(after)) = (options.threshold, &action.screenshot_after) {
+            let recorded = trace.screenshot(after)?;
+            let current = img_shrink(env.screenshot()?, recorded.width(), recorded.height());
+            let difference = image_difference(&recorded, &current);
+            println!("  Difference from recording: {:.3}", difference);
+            if difference > threshold {
+                return Err(anyhow!(Diverged { index: action.index, difference, threshold }));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sleep in short steps, so that an interruption isn't delayed by long recorded pauses
+fn sleep(duration: Duration, options: &ReplayOptions) -> Result<()> {
+    let until = Instant::now() + duration;
+    while let Some(remaining) = until.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) {
+        if options.interrupted.load(Ordering::Relaxed) {
+            return Err(Interrupted.into());
+        }
+        std::thread::sleep(remaining.min(Duration::from_millis(100)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::ToolInput;
+    use crate::env::fake::{fill, Call, FakeEnvironment};
+    use crate::trace::{TraceHeader, TraceWriter, TracedAction, TRACE_VERSION};
+    use image::{Rgb, RgbImage};
+
+    const GRAY: Rgb<u8> = Rgb([240, 240, 240]);
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+
+    /// A trace of a 640x480 session with half size screenshots, where a click at (200, 100)
+    /// turned the screen red. A key press that failed precedes it and Return is pressed after it.
+    fn record_trace(dir: &std::path::Path) -> Trace {
+        let header = TraceHeader {
+            version: TRACE_VERSION,
+            session_id: "test".to_string(),
+            model: "mock".to_string(),
+            environment: "fake".to_string(),
+            screen_width: 640,
+            screen_height: 480,
+            started_at: "2025-06-01T12:00:00+02:00".to_string(),
+        };
+        let mut writer = TraceWriter::create(dir, header).unwrap();
+        let before = RgbImage::from_pixel(320, 240, GRAY);
+        let after = RgbImage::from_pixel(320, 240, RED);
+        writer.record(TracedAction {
+            tool_use_id: "toolu_01", input: &ToolInput::Key { text: "notakey".to_string() }, scale: 0.5,
+            before: &before, after: None, output: None, error: Some("Unknown key: notakey".to_string())
+        }).unwrap();
+        writer.record(TracedAction {
+            tool_use_id: "toolu_02", input: &ToolInput::LeftClick { coordinate: Some([100, 50]), text: None }, scale: 0.5,
+            before: &before, after: Some(&after), output: None, error: None
+        }).unwrap();
+        writer.record(TracedAction {
+            tool_use_id: "toolu_03", input: &ToolInput::Key { text: "Return".to_string() }, scale: 0.5,
+            before: &after, after: Some(&after), output: None, error: None
+        }).unwrap();
+        Trace::load(dir).unwrap()
+    }
+
+    fn options() -> ReplayOptions {
+        ReplayOptions { recorded_timing: false, threshold: Some(0.05), interrupted: Default::default() }
+    }
+
+    #[test]
+    fn replays_successful_actions() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = record_trace(dir.path());
+        let mut env = FakeEnvironment::create(640, 480)
+            .on_click((180, 80, 40, 40), |screen| fill(screen, (0, 0, 640, 480), RED));
+        replay(&mut env, &trace, &options()).unwrap();
+        assert_eq!(*env.calls.borrow(), [
+            Call::MouseMove { x: 200, y: 100 },
+            Call::LeftClick { x: 200, y: 100 },
+            Call::Wait(SETTLE_DELAY),
+            Call::PressKey("Return".to_string()),
+            Call::Wait(SETTLE_DELAY),
+        ]);
+    }
+
+    #[test]
+    fn compares_screenshots_once_the_ui_settled() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = record_trace(dir.path());
+        // The screen only turns red once the environment waited after the click
+        let mut env = FakeEnvironment::create(640, 480)
+            .on_click((180, 80, 40, 40), |screen| fill(screen, (0, 0, 640, 480), RED))
+            .animated();
+        let options = ReplayOptions { recorded_timing: true, ..options() };
+        replay(&mut env, &trace, &options).unwrap();
+        assert_eq!(env.calls.borrow()[2], Call::Wait(SETTLE_DELAY));
+    }
+
+    #[test]
+    fn stops_when_the_screen_diverges() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = record_trace(dir.path());
+        let mut env = FakeEnvironment::create(640, 480);
+        let error = replay(&mut env, &trace, &options()).unwrap_err();
+        let diverged = error.downcast_ref::<Diverged>().unwrap();
+        assert_eq!(diverged.index, 2);
+    }
+
+    #[test]
+    fn stops_when_interrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = record_trace(dir.path());
+        let mut env = FakeEnvironment::create(640, 480);
+        let options = options();
+        options.interrupted.store(true, Ordering::Relaxed);
+        let error = replay(&mut env, &trace, &options).unwrap_err();
+        assert!(error.is::<Interrupted>());
+        assert!(env.calls.borrow().is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose;
+use base64::Engine as _;
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use crate::agent::Message;
+
+const TRANSCRIPT_FILE: &str = "transcript.json";
+const SCREENSHOTS_DIR: &str = "screenshots";
+const TRANSCRIPT_VERSION: u32 = 1;
+
+/// `$XDG_STATE_HOME/ui-act/sessions`, defaulting to `~/.local/state/ui-act/sessions`
+pub fn sessions_dir() -> Option<PathBuf> {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state")))?;
+    Some(state_home.join("ui-act").join("sessions"))
+}
+
+/// The transcript of a session on disk, as `transcript.json` with the screenshots stored
+/// as separate PNG files next to it, so that the session can be inspected and resumed.
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn open(session_id: &str) -> Result<Self> {
+        let dir = sessions_dir()
+            .ok_or_else(|| anyhow!("Unable to determine the state directory, set XDG_STATE_HOME"))?
+            .join(session_id);
+        Ok(SessionStore { dir })
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    /// Write the transcript, replacing the previous one
+    pub fn save(&self, model: &str, messages: &[Message]) -> Result<()> {
+        std::fs::create_dir_all(self.dir.join(SCREENSHOTS_DIR))
+            .with_context(|| format!("Failed to create session directory {}", self.dir.display()))?;
+        let mut messages = serde_json::to_value(messages)?;
+        self.extract_images(&mut messages)?;
+        let transcript = json!({
+            "version": TRANSCRIPT_VERSION,
+            "model": model,
+            "messages": messages
+        });
+
+        // Write to a temporary file first, so a crash never leaves a truncated transcript
+        let path = self.dir.join(TRANSCRIPT_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&transcript)?)?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Read the transcript back into the messages that were sent to the model
+    pub fn load(&self) -> Result<Vec<Message>> {
+        let path = self.dir.join(TRANSCRIPT_FILE);
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read session transcript {}", path.display()))?;
+        let mut transcript: Value = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse session transcript {}", path.display()))?;
+        if transcript["version"] != TRANSCRIPT_VERSION {
+            return Err(anyhow!("Unsupported session transcript version: {}", transcript["version"]));
+        }
+        let mut messages = transcript["messages"].take();
+        self.inline_images(&mut messages)?;
+        Ok(serde_json::from_value(messages)?)
+    }
+
+    /// Replace base64 image data with references to PNG files in the screenshots directory.
+    /// Files are named by content, so images already written by a previous save are reused.
+    fn extract_images(&self, value: &mut Value) -> Result<()> {
+        match value {
+            Value::Array(values) => values.iter_mut().try_for_each(|v| self.extract_images(v)),
+            Value::Object(object) if object.get("type") == Some(&json!("image")) => {
+                let Some(data) = object["source"]["data"].as_str() else {
+                    return Ok(());
+                };
+                let mut hasher = DefaultHasher::new();
+                data.hash(&mut hasher);
+                let file = format!("{}/{:016x}.png", SCREENSHOTS_DIR, hasher.finish());
+                let path = self.dir.join(&file);
+                if !path.exists() {
+                    std::fs::write(&path, general_purpose::STANDARD.decode(data)?)?;
+                }
+                object.insert("source".to_string(), json!({ "type": "file", "path": file }));
+                Ok(())
+            }
+            Value::Object(object) => object.values_mut().try_for_each(|v| self.extract_images(v)),
+            _ => Ok(())
+        }
+    }
+
+    fn inline_images(&self, value: &mut Value) -> Result<()> {
+        match value {
+            Value::Array(values) => values.iter_mut().try_for_each(|v| self.inline_images(v)),
+            Value::Object(object) if object.get("type") == Some(&json!("image")) => {
+                let Some(file) = object["source"]["path"].as_str() else {
+                    return Ok(());
+                };
+                let path = self.dir.join(file);
+                let png = std::fs::read(&path)
+                    .with_context(|| format!("Failed to read screenshot {}", path.display()))?;
+                object.insert("source".to_string(), json!({
+                    "type": "base64",
+                    "media_type": "image/png",
+                    "data": general_purpose::STANDARD.encode(png)
+                }));
+                Ok(())
+            }
+            Value::Object(object) => object.values_mut().try_for_each(|v| self.inline_images(v)),
+            _ => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::ContentBlock;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn stores_screenshots_as_files_and_reads_them_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore { dir: dir.path().to_path_buf() };
+        let screenshot = ContentBlock::png_image(&RgbImage::from_pixel(32, 24, Rgb([255, 0, 0]))).unwrap();
+        let messages = vec![
+            Message { role: "user".to_string(), content: vec![
+                ContentBlock::Text { text: "Close the dialog".to_string() },
+                screenshot.clone()
+            ]},
+            Message { role: "user".to_string(), content: vec![ContentBlock::ToolResult {
+                content: vec![screenshot],
+                tool_use_id: "toolu_01".to_string(),
+                is_error: false
+            }]},
+        ];
+        store.save("mock", &messages).unwrap();
+
+        // The same screenshot is written once, and referenced instead of inlined
+        let files: Vec<_> = std::fs::read_dir(dir.path().join(SCREENSHOTS_DIR)).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let transcript: Value = serde_json::from_str(&std::fs::read_to_string(dir.path().join(TRANSCRIPT_FILE)).unwrap()).unwrap();
+        assert_eq!(transcript["messages"][0]["content"][1]["source"]["type"], "file");
+        assert_eq!(transcript["messages"][1]["content"][0]["content"][0]["source"]["type"], "file");
+
+        let loaded = store.load().unwrap();
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&messages).unwrap());
+    }
+
+    #[test]
+    fn rejects_unknown_transcript_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore { dir: dir.path().to_path_buf() };
+        std::fs::write(dir.path().join(TRANSCRIPT_FILE), r#"{ "version": 99, "model": "mock", "messages": [] }"#).unwrap();
+        assert!(store.load().is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use crate::agent::{ToolInput, to_env_coordinate};
+
+/// Version of the trace format, bumped on incompatible changes
+pub const TRACE_VERSION: u32 = 1;
+const TRACE_FILE: &str = "trace.jsonl";
+const SCREENSHOTS_DIR: &str = "screenshots";
+
+/// A line of `trace.jsonl`. The first line is the header, followed by one line per action.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceEvent {
+    Header(TraceHeader),
+    Action(ActionRecord),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceHeader {
+    pub version: u32,
+    pub session_id: String,
+    pub model: String,
+    pub environment: String,
+    /// Size of the environment, which environment-space coordinates are relative to
+    pub screen_width: u32,
+    pub screen_height: u32,
+    pub started_at: String,
+}
+
+/// An action executed in the environment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionRecord {
+    pub index: u32,
+    /// RFC 3339 time at which the action was executed
+    pub timestamp: String,
+    pub elapsed_ms: u64,
+    pub tool_use_id: String,
+    pub input: ToolInput,
+    /// Coordinate as sent by the model, in screenshot space
+    pub model_coordinate: Option<[u32; 2]>,
+    /// The same coordinate in environment space, i.e. `model_coordinate / scale`
+    pub env_coordinate: Option<[u32; 2]>,
+    /// Screenshot width divided by environment width
+    pub scale: f32,
+    /// Screenshots as seen by the model, relative to the trace directory
+    pub screenshot_before: String,
+    /// Missing if the action failed
+    pub screenshot_after: Option<String>,
+    /// Text output of the action, e.g. for cursor_position
+    pub output: Option<String>,
+    pub error: Option<String>,
+}
+
+/// An executed action to record
+pub struct TracedAction<'a> {
+    pub tool_use_id: &'a str,
+    pub input: &'a ToolInput,
+    pub scale: f32,
+    pub before: &'a image::RgbImage,
+    pub after: Option<&'a image::RgbImage>,
+    pub output: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Writes a trace directory with `trace.jsonl` and the screenshots in `screenshots/`
+pub struct TraceWriter {
+    dir: PathBuf,
+    file: File,
+    index: u32,
+    started: Instant,
+}
+
+impl TraceWriter {
+    pub fn create(dir: &Path, header: TraceHeader) -> Result<Self> {
+        std::fs::create_dir_all(dir.join(SCREENSHOTS_DIR))
+            .with_context(|| format!("Failed to create trace directory {}", dir.display()))?;
+        let file = File::create(dir.join(TRACE_FILE))
+            .with_context(|| format!("Failed to create trace file in {}", dir.display()))?;
+        let mut writer = TraceWriter { dir: dir.to_path_buf(), file, index: 0, started: Instant::now() };
+        writer.write_event(&TraceEvent::Header(header))?;
+        Ok(writer)
+    }
+
+    /// Record an action along with the screenshots before and after it
+    pub fn record(&mut self, action: TracedAction) -> Result<()> {
+        self.index += 1;
+        let screenshot_before = self.save_screenshot(action.before, "before")?;
+        let screenshot_after = action.after.map(|after| self.save_screenshot(after, "after")).transpose()?;
+        let model_coordinate = action.input.coordinate();
+        let scale = action.scale;
+        let record = ActionRecord {
+            index: self.index,
+            timestamp: chrono::Local::now().to_rfc3339(),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            tool_use_id: action.tool_use_id.to_string(),
+            input: action.input.clone(),
+            model_coordinate,
+            env_coordinate: model_coordinate.map(|coordinate| {
+                let (x, y) = to_env_coordinate(&coordinate, scale);
+                [x, y]
+            }),
+            scale,
+            screenshot_before,
+            screenshot_after,
+            output: action.output,
+            error: action.error,
+        };
+        self.write_event(&TraceEvent::Action(record))
+    }
+
+    fn save_screenshot(&self, image: &image::RgbImage, suffix: &str) -> Result<String> {
+        let file = format!("{}/{:04}-{}.png", SCREENSHOTS_DIR, self.index, suffix);
+        image.save(self.dir.join(&file))?;
+        Ok(file)
+    }
+
+    fn write_event(&mut self, event: &TraceEvent) -> Result<()> {
+        // One line per event, flushed right away so the trace survives a crash
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// A trace read back from disk
+pub struct Trace {
+    pub dir: PathBuf,
+    pub header: TraceHeader,
+    pub actions: Vec<ActionRecord>,
+}
+
+impl Trace {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(TRACE_FILE);
+        let file = File::open(&path)
+            .with_context(|| format!("Failed to open trace {}", path.display()))?;
+        let mut header = None;
+        let mut actions = vec![];
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: TraceEvent = serde_json::from_str(&line)
+                .with_context(|| format!("Invalid trace event on line {}", i + 1))?;
+            match event {
+                TraceEvent::Header(h) if h.version != TRACE_VERSION => {
+                    return Err(anyhow!("Unsupported trace version {}, expected {}", h.version, TRACE_VERSION));
+                }
+                TraceEvent::Header(h) => header = Some(h),
+                TraceEvent::Action(action) => actions.push(action),
+            }
+        }
+        let header = header.ok_or_else(|| anyhow!("Trace {} has no header", path.display()))?;
+        Ok(Trace { dir: dir.to_path_buf(), header, actions })
+    }
+
+    pub fn screenshot(&self, file: &str) -> Result<image::RgbImage> {
+        let path = self.dir.join(file);
+        Ok(image::open(&path)
+            .with_context(|| format!("Failed to read screenshot {}", path.display()))?
+            .to_rgb8())
+    }
+}
@@ -40,3 +40,33 @@ pub fn rgb_image_to_base64_png(img: &image::RgbImage) -> Result<String> {
     let base64_string = general_purpose::STANDARD.encode(&buffer);
     Ok(base64_string)
 }
+
+/// Mean absolute difference of two images, from 0 (identical) to 1. The second image is
+/// resized to the size of the first if they differ.
+pub fn image_difference(a: &RgbImage, b: &RgbImage) -> f64 {
+    let resized;
+    let b = if a.dimensions() != b.dimensions() {
+        resized = resize(b, a.width(), a.height(), FilterType::Triangle);
+        &resized
+    } else {
+        b
+    };
+    let total: u64 = a.as_raw().iter().zip(b.as_raw().iter())
+        .map(|(x, y)| x.abs_diff(*y) as u64)
+        .sum();
+    total as f64 / (a.as_raw().len().max(1) as f64 * 255.0)
+}
+
+/// Capture a window with GetImage, for X servers that xcap can't reach, like a nested display
+pub fn capture_x11_window<C: x11rb::connection::Connection>(conn: &C, window: u32) -> Result<RgbImage> {
+    use x11rb::protocol::xproto::{ConnectionExt, ImageFormat, ImageOrder};
+    let geom = conn.get_geometry(window)?.reply()?;
+    let (width, height) = (geom.width as u32, geom.height as u32);
+    let reply = conn.get_image(ImageFormat::Z_PIXMAP, window, 0, 0, geom.width, geom.height, !0)?.reply()?;
+    // 24 bit color is stored as 32 bits per pixel, BGRX on little endian servers
+    if reply.data.len() != (width * height * 4) as usize || conn.setup().image_byte_order != ImageOrder::LSB_FIRST {
+        return Err(anyhow!("Unsupported image format (depth {}), the X server must use 24 bit color", reply.depth));
+    }
+    let rgb = reply.data.chunks_exact(4).flat_map(|bgrx| [bgrx[2], bgrx[1], bgrx[0]]).collect();
+    RgbImage::from_raw(width, height, rgb).ok_or_else(|| anyhow!("Invalid image data"))
+}
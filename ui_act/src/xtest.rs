@@ -0,0 +1,227 @@
+use anyhow::{anyhow, Result};
+use std::thread;
+use std::time::Duration;
+use uinput::event::Code;
+use uinput::event::keyboard::Key;
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::xinput::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{ConnectionExt as _, BUTTON_PRESS_EVENT, BUTTON_RELEASE_EVENT, KEY_PRESS_EVENT, KEY_RELEASE_EVENT, MOTION_NOTIFY_EVENT};
+use x11rb::protocol::xtest::ConnectionExt as _;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+use crate::device::{char_to_keys, get_device_id_by_name, parse_key_combination, MouseButton, ScrollDirection, XInputMaster, CLICK_DELAY, KEY_PRESS_DELAY, MULTI_CLICK_DELAY};
+use crate::input::{master_cursor_position, InputBackend, MPXInput};
+
+/// An xinput master that XTest events are routed to, through its XTEST slave devices
+struct XTestMaster {
+    master: XInputMaster,
+    pointer_id: u8,
+    keyboard_id: u8,
+    /// First event code of the XInput extension, for device events
+    xi_event_base: u8,
+}
+
+/// Input injected with the XTest extension. Without an attached master it acts as the core
+/// pointer and keyboard, which also works in servers without input devices like Xvfb.
+pub struct XTestInput {
+    conn: RustConnection,
+    root: u32,
+    master: Option<XTestMaster>,
+}
+
+impl XTestInput {
+    /// Connect to `display`, or $DISPLAY if not given
+    pub fn create(display: Option<&str>) -> Result<Self> {
+        let (conn, screen_num) = x11rb::connect(display)?;
+        conn.xtest_get_version(2, 2)?.reply()
+            .map_err(|e| anyhow!("The XTest extension is not available: {}", e))?;
+        let root = conn.setup().roots[screen_num].root;
+        Ok(XTestInput { conn, root, master: None })
+    }
+
+    /// Send all further input through the xinput master `name`, creating it if needed.
+    /// Motion warps the master pointer with XI2, while buttons and keys are sent as XInput
+    /// device events to the XTEST devices of the master.
+    pub fn attach_master(&mut self, name: &str) -> Result<()> {
+        MPXInput::init_xi2(&self.conn)?;
+        let xi_event_base = self.conn.extension_information(xinput::X11_EXTENSION_NAME)?
+            .ok_or_else(|| anyhow!("The XInput extension is not available"))?
+            .first_event;
+        let master = XInputMaster::create(name)?;
+        let pointer_id = get_device_id_by_name(&format!("{} XTEST pointer", name))? as u8;
+        let keyboard_id = get_device_id_by_name(&format!("{} XTEST keyboard", name))? as u8;
+        self.master = Some(XTestMaster { master, pointer_id, keyboard_id, xi_event_base });
+        Ok(())
+    }
+
+    fn fake_input(&self, type_: u8, detail: u8, x: i16, y: i16, deviceid: u8) -> Result<()> {
+        self.conn.xtest_fake_input(type_, detail, x11rb::CURRENT_TIME, self.root, x, y, deviceid)?;
+        // Wait until the server has processed the event
+        self.conn.sync()?;
+        Ok(())
+    }
+
+    fn button(&self, button: u8, press: bool) -> Result<()> {
+        match &self.master {
+            Some(m) => {
+                let event = if press { xinput::DEVICE_BUTTON_PRESS_EVENT } else { xinput::DEVICE_BUTTON_RELEASE_EVENT };
+                self.fake_input(m.xi_event_base + event, button, 0, 0, m.pointer_id)
+            }
+            None => self.fake_input(if press { BUTTON_PRESS_EVENT } else { BUTTON_RELEASE_EVENT }, button, 0, 0, 0),
+        }
+    }
+
+    fn key(&self, key: Key, press: bool) -> Result<()> {
+        match &self.master {
+            Some(m) => {
+                let event = if press { xinput::DEVICE_KEY_PRESS_EVENT } else { xinput::DEVICE_KEY_RELEASE_EVENT };
+                self.fake_input(m.xi_event_base + event, keycode(key), 0, 0, m.keyboard_id)
+            }
+            None => self.fake_input(if press { KEY_PRESS_EVENT } else { KEY_RELEASE_EVENT }, keycode(key), 0, 0, 0),
+        }
+    }
+}
+
+impl InputBackend for XTestInput {
+    fn mouse_move(&mut self, x: u32, y: u32) -> Result<()> {
+        match &self.master {
+            Some(m) => {
+                // Coordinates are 16.16 fixed point
+                self.conn.xinput_xi_warp_pointer(x11rb::NONE, self.root, 0, 0, 0, 0, (x as i32) << 16, (y as i32) << 16, m.master.pointer_id as u16)?;
+                self.conn.sync()?;
+                Ok(())
+            }
+            None => self.fake_input(MOTION_NOTIFY_EVENT, 0, x as i16, y as i16, 0),
+        }
+    }
+
+    fn scroll(&mut self, scroll_direction: ScrollDirection, amount: u32) -> Result<()> {
+        // Scrolling is done with buttons 4 to 7, one click per step
+        let button = match scroll_direction {
+            ScrollDirection::Up => 4,
+            ScrollDirection::Down => 5,
+            ScrollDirection::Left => 6,
+            ScrollDirection::Right => 7,
+        };
+        for _ in 0..amount {
+            self.button(button, true)?;
+            self.button(button, false)?;
+        }
+        Ok(())
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) -> Result<()> {
+        self.button(button_number(button), true)
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) -> Result<()> {
+        self.button(button_number(button), false)
+    }
+
+    fn click(&mut self, button: MouseButton) -> Result<()> {
+        let button = button_number(button);
+        self.button(button, true)?;
+        thread::sleep(CLICK_DELAY);
+        self.button(button, false)
+    }
+
+    fn click_drag(&mut self, button: MouseButton, x: u32, y: u32) -> Result<()> {
+        let button = button_number(button);
+        self.button(button, true)?;
+        thread::sleep(CLICK_DELAY);
+        self.mouse_move(x, y)?;
+        thread::sleep(CLICK_DELAY);
+        self.button(button, false)
+    }
+
+    fn double_click(&mut self) -> Result<()> {
+        self.click(MouseButton::Left)?;
+        thread::sleep(MULTI_CLICK_DELAY);
+        self.click(MouseButton::Left)
+    }
+
+    fn triple_click(&mut self) -> Result<()> {
+        self.double_click()?;
+        thread::sleep(MULTI_CLICK_DELAY);
+        self.click(MouseButton::Left)
+    }
+
+    fn keys_down(&mut self, key_combination: &str) -> Result<()> {
+        for key in parse_key_combination(key_combination)?.keys {
+            self.key(key, true)?;
+        }
+        Ok(())
+    }
+
+    fn keys_up(&mut self, key_combination: &str) -> Result<()> {
+        for key in parse_key_combination(key_combination)?.keys.into_iter().rev() {
+            self.key(key, false)?;
+        }
+        Ok(())
+    }
+
+    fn hold_key(&mut self, key_combination: &str, duration: Duration) -> Result<()> {
+        self.keys_down(key_combination)?;
+        thread::sleep(duration);
+        self.keys_up(key_combination)
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            let keys = char_to_keys(c);
+            if keys.is_empty() {
+                continue; // skip unsupported chars
+            }
+            for key in keys.iter() {
+                self.key(*key, true)?;
+            }
+            thread::sleep(KEY_PRESS_DELAY);
+            for key in keys.iter().rev() {
+                self.key(*key, false)?;
+            }
+            thread::sleep(KEY_PRESS_DELAY);
+        }
+        Ok(())
+    }
+
+    fn press_key(&mut self, key_combination: &str) -> Result<()> {
+        self.keys_down(key_combination)?;
+        thread::sleep(KEY_PRESS_DELAY);
+        self.keys_up(key_combination)
+    }
+
+    fn cursor_position(&self, window: u32) -> Result<(i32, i32)> {
+        match &self.master {
+            Some(m) => master_cursor_position(&self.conn, &m.master, window),
+            None => {
+                let reply = self.conn.query_pointer(window)?.reply()?;
+                Ok((reply.win_x as i32, reply.win_y as i32))
+            }
+        }
+    }
+}
+
+impl Drop for XTestInput {
+    fn drop(&mut self) {
+        // Like with uinput, the master is kept, so park its pointer in the bottom right corner
+        if self.master.is_some() {
+            let screen = self.conn.setup().roots.iter().find(|screen| screen.root == self.root);
+            if let Some((width, height)) = screen.map(|s| (s.width_in_pixels as u32, s.height_in_pixels as u32)) {
+                let _ = self.mouse_move(width - 1, height - 1);
+            }
+        }
+    }
+}
+
+fn button_number(button: MouseButton) -> u8 {
+    match button {
+        MouseButton::Left => 1,
+        MouseButton::Middle => 2,
+        MouseButton::Right => 3,
+    }
+}
+
+/// X keycode of a key, assuming the evdev keycodes that Xorg, Xvfb and Xephyr use by default
+fn keycode(key: Key) -> u8 {
+    (key.code() + 8) as u8
+}